@@ -0,0 +1,164 @@
+use crate::admin::AdminNodeType;
+use crate::credential_store::{CredentialStore, CredentialStoreError};
+use crate::security::{CertificateOptions, NodeCa, TlsIdentity, TlsIdentityError};
+use clap::Subcommand;
+use goliath_common::core::NodeType;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CertificateCommandError {
+    #[error(transparent)]
+    TlsIdentity(#[from] TlsIdentityError),
+    #[error(transparent)]
+    CredentialStore(#[from] CredentialStoreError),
+    #[error(
+        "{0:?} can't be used as a file name, node ids must not contain path separators or \"..\""
+    )]
+    InvalidNodeId(String),
+    #[error("{0:?} {1} is not in the credential store, add it with `admin add` first or its certificate won't be accepted")]
+    UnknownNode(NodeType, String),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CertificateAction {
+    /// Print the certificate's fingerprint and expiry date, generating it if it doesn't exist yet
+    Show,
+    /// Issue a new certificate for the existing key, nodes pinning the old certificate must be updated
+    Renew,
+    /// Replace both the key and the certificate, nodes pinning the old certificate must be updated
+    Regenerate,
+    /// Issue a node certificate from the node CA for a node that is already in the credential store,
+    /// the node can then register without its secret key
+    Issue {
+        node_type: AdminNodeType,
+        id: String,
+        /// Where `<id>.pem` and `<id>_key.pem` are written
+        #[arg(long, default_value = ".")]
+        out_dir: PathBuf,
+    },
+}
+
+fn print_identity(identity: &TlsIdentity) -> Result<(), TlsIdentityError> {
+    println!("Fingerprint (SHA-256): {}", identity.fingerprint());
+    println!("Valid until: {}", identity.not_after()?);
+    Ok(())
+}
+
+// The id ends up in the names of the files that are written, so it must not lead anywhere else
+fn is_valid_file_name(id: &str) -> bool {
+    !id.is_empty() && !id.contains(['/', '\\']) && !id.contains("..")
+}
+
+pub fn run(
+    cert_path: &Path,
+    key_path: &Path,
+    ca_paths: (&Path, &Path),
+    store_path: &Path,
+    options: &CertificateOptions,
+    action: CertificateAction,
+) -> Result<(), CertificateCommandError> {
+    let identity = match action {
+        CertificateAction::Show => TlsIdentity::load_or_generate(cert_path, key_path, options)?,
+        CertificateAction::Renew => {
+            let mut identity = TlsIdentity::load(cert_path, key_path)?;
+            identity.renew(options)?;
+            identity.save(cert_path, key_path)?;
+            println!("Renewed {}", cert_path.display());
+            identity
+        }
+        CertificateAction::Regenerate => {
+            let identity = TlsIdentity::generate(options)?;
+            identity.save(cert_path, key_path)?;
+            println!(
+                "Regenerated {} and {}",
+                cert_path.display(),
+                key_path.display()
+            );
+            identity
+        }
+        CertificateAction::Issue {
+            node_type,
+            id,
+            out_dir,
+        } => {
+            if !is_valid_file_name(&id) {
+                return Err(CertificateCommandError::InvalidNodeId(id));
+            }
+            // Certificate authentication still requires the node to be in the store
+            if !CredentialStore::load(store_path)?.contains(node_type.into(), &id)? {
+                return Err(CertificateCommandError::UnknownNode(node_type.into(), id));
+            }
+
+            let (ca_cert_path, ca_key_path) = ca_paths;
+            let identity = NodeCa::load_or_generate(ca_cert_path, ca_key_path)?
+                .issue(NodeType::from(node_type), &id)?;
+            let (node_cert_path, node_key_path) = (
+                out_dir.join(format!("{id}.pem")),
+                out_dir.join(format!("{id}_key.pem")),
+            );
+            identity.save(&node_cert_path, &node_key_path)?;
+            println!(
+                "Issued {} and {} for {:?} {id}",
+                node_cert_path.display(),
+                node_key_path.display(),
+                NodeType::from(node_type)
+            );
+            identity
+        }
+    };
+
+    Ok(print_identity(&identity)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run, CertificateAction, CertificateCommandError};
+    use crate::admin::AdminNodeType;
+    use crate::credential_store::CredentialStore;
+    use crate::security::{generate_secret_key, CertificateOptions};
+    use goliath_common::core::NodeType;
+
+    #[test]
+    fn test_issue_checks_the_node() {
+        let dir = tempfile::tempdir().unwrap();
+        let store_path = dir.path().join("credentials.json");
+        let mut store = CredentialStore::load(&store_path).unwrap();
+        store
+            .insert_node(NodeType::Vehicle, "Goliath1", &generate_secret_key())
+            .unwrap();
+        store.save().unwrap();
+
+        let issue = |id: &str| {
+            run(
+                &dir.path().join("cert.pem"),
+                &dir.path().join("key.pem"),
+                (&dir.path().join("ca.pem"), &dir.path().join("ca_key.pem")),
+                &store_path,
+                &CertificateOptions::default(),
+                CertificateAction::Issue {
+                    node_type: AdminNodeType::Vehicle,
+                    id: id.to_string(),
+                    out_dir: dir.path().join("issued"),
+                },
+            )
+        };
+
+        for id in ["../Goliath1", "nodes/Goliath1", ".."] {
+            assert!(matches!(
+                issue(id),
+                Err(CertificateCommandError::InvalidNodeId(_))
+            ));
+        }
+        assert!(matches!(
+            issue("Goliath2"),
+            Err(CertificateCommandError::UnknownNode(NodeType::Vehicle, _))
+        ));
+        assert!(!dir.path().join("issued").exists());
+
+        std::fs::create_dir(dir.path().join("issued")).unwrap();
+        issue("Goliath1").unwrap();
+        assert!(dir.path().join("issued/Goliath1.pem").exists());
+        assert!(dir.path().join("issued/Goliath1_key.pem").exists());
+    }
+}
@@ -0,0 +1,108 @@
+pub mod certificate;
+
+use crate::credential_store::{CredentialStore, CredentialStoreError};
+use crate::security::generate_secret_key;
+use clap::{Subcommand, ValueEnum};
+use goliath_common::core::NodeType;
+use std::path::Path;
+
+#[derive(Copy, Clone, Debug, ValueEnum)]
+pub enum AdminNodeType {
+    Client,
+    Vehicle,
+}
+
+impl From<AdminNodeType> for NodeType {
+    fn from(node_type: AdminNodeType) -> Self {
+        match node_type {
+            AdminNodeType::Client => NodeType::Client,
+            AdminNodeType::Vehicle => NodeType::Vehicle,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AdminAction {
+    /// Register a new node, and print its freshly generated secret key
+    Add {
+        node_type: AdminNodeType,
+        id: String,
+        /// The base64 secret a node that predates the challenge-response handshake is already
+        /// configured with. It is stored as is, and only accepted while `registration.allow_legacy_auth` is on
+        #[arg(long, value_name = "SECRET_KEY")]
+        legacy_secret: Option<String>,
+    },
+    /// Remove a node, it will no longer be able to register
+    Revoke {
+        node_type: AdminNodeType,
+        id: String,
+    },
+    /// Generate a new secret key for an existing node, the previous one stops working
+    Rotate {
+        node_type: AdminNodeType,
+        id: String,
+    },
+    /// Print every registered node
+    List,
+}
+
+fn print_secret_key(node_type: NodeType, id: &str, secret_key: &str) {
+    println!("{node_type:?} {id} secret key: {secret_key}");
+    println!("Only a key derived from it is stored, so it can't be displayed again");
+}
+
+pub fn run(store_path: &Path, action: AdminAction) -> Result<(), CredentialStoreError> {
+    let mut store = CredentialStore::load(store_path)?;
+
+    match action {
+        AdminAction::Add {
+            node_type,
+            id,
+            legacy_secret: Some(secret_key),
+        } => {
+            store.insert_legacy_node(node_type.into(), &id, &secret_key)?;
+            store.save()?;
+            println!(
+                "Added {:?} {id} for legacy authentication, rotate it once it is updated",
+                NodeType::from(node_type)
+            );
+        }
+        AdminAction::Add {
+            node_type,
+            id,
+            legacy_secret: None,
+        } => {
+            let secret_key = generate_secret_key();
+            store.insert_node(node_type.into(), &id, &secret_key)?;
+            store.save()?;
+            print_secret_key(node_type.into(), &id, &secret_key);
+        }
+        AdminAction::Revoke { node_type, id } => {
+            store.remove_node(node_type.into(), &id)?;
+            store.save()?;
+            println!("Revoked {:?} {id}", NodeType::from(node_type));
+        }
+        AdminAction::Rotate { node_type, id } => {
+            let secret_key = generate_secret_key();
+            store.rotate_node(node_type.into(), &id, &secret_key)?;
+            store.save()?;
+            print_secret_key(node_type.into(), &id, &secret_key);
+        }
+        AdminAction::List => {
+            if store.is_empty() {
+                println!("No nodes registered in {}", store_path.display());
+            }
+            let legacy_nodes = store.legacy_nodes();
+            for node in store.nodes() {
+                let (node_type, id) = node;
+                if legacy_nodes.contains(&node) {
+                    println!("{node_type:?}\t{id}\t(legacy, rotate once updated)");
+                } else {
+                    println!("{node_type:?}\t{id}");
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,480 @@
+use crate::listener::HandshakePolicy;
+use crate::security::{CertificateOptions, ClientAuth, KeyAlgorithm};
+use crate::server_core::{DuplicateIdPolicy, IdentityPolicy, RegistrationPolicy, SessionPolicy};
+use clap::Args;
+use goliath_common::websocket::HeartbeatPolicy;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::time::Duration;
+use thiserror::Error;
+
+const DEFAULT_CONFIG: &str = "goliath_backend.toml";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Could not read config file {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("Config file {0} is malformed: {1}")]
+    Malformed(PathBuf, toml::de::Error),
+    #[error("Invalid configuration:\n  {}", .0.join("\n  "))]
+    Invalid(Vec<String>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub node_ca_cert: PathBuf,
+    pub node_ca_key: PathBuf,
+    pub client_auth: ClientAuth,
+    pub key_algorithm: KeyAlgorithm,
+    pub subject_alt_names: Vec<String>,
+    pub validity_days: u32,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        let certificate_options = CertificateOptions::default();
+        Self {
+            cert: "goliath_cert.pem".into(),
+            key: "goliath_key.pem".into(),
+            node_ca_cert: "goliath_node_ca.pem".into(),
+            node_ca_key: "goliath_node_ca_key.pem".into(),
+            client_auth: ClientAuth::default(),
+            key_algorithm: certificate_options.algorithm,
+            subject_alt_names: certificate_options.subject_alt_names,
+            validity_days: certificate_options.validity_days,
+        }
+    }
+}
+
+impl TlsConfig {
+    pub fn certificate_options(&self) -> CertificateOptions {
+        CertificateOptions {
+            algorithm: self.key_algorithm,
+            subject_alt_names: self.subject_alt_names.clone(),
+            validity_days: self.validity_days,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistrationConfig {
+    /// How far a registration timestamp may drift from the server clock
+    pub window_secs: u64,
+    /// Accepts the unkeyed hash from nodes that predate the challenge-response handshake, which
+    /// have to be added with `admin add --legacy-secret`. Only meant to be switched on while such
+    /// nodes are being updated, the backend won't start without it while any of them is left
+    pub allow_legacy_auth: bool,
+}
+
+impl Default for RegistrationConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 30,
+            allow_legacy_auth: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /// How many sessions may run at once, unlimited when left out
+    pub max_sessions: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    /// How often nodes are pinged
+    pub interval_secs: u64,
+    /// How long a node may stay silent before it is dropped
+    pub timeout_secs: u64,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 5,
+            timeout_secs: 15,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConnectionConfig {
+    /// How long a new connection gets to complete the TLS handshake and websocket upgrade,
+    /// and then again to register
+    pub handshake_timeout_secs: u64,
+    /// How many connections may be handshaking or registering at once, connections beyond that are turned away
+    pub max_handshakes: usize,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            handshake_timeout_secs: 10,
+            max_handshakes: 64,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// How long connected nodes get to be notified and disconnected before the backend exits anyway
+    pub grace_period_secs: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            grace_period_secs: 5,
+        }
+    }
+}
+
+/// What happens when a node registers with an id that is already connected, per node type
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DuplicateIdConfig {
+    pub clients: DuplicateIdPolicy,
+    pub vehicles: DuplicateIdPolicy,
+}
+
+/// Everything the backend can be configured with, read from a TOML file, then overridden by
+/// environment variables and finally by command line flags
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendConfig {
+    /// `::` listens on IPv6, and on IPv4 too on systems with dual-stack sockets
+    pub bind_address: IpAddr,
+    pub port: u16,
+    pub log_level: LevelFilter,
+    pub credential_store: PathBuf,
+    pub tls: TlsConfig,
+    pub registration: RegistrationConfig,
+    pub sessions: SessionConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub connections: ConnectionConfig,
+    pub shutdown: ShutdownConfig,
+    pub duplicate_ids: DuplicateIdConfig,
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 8555,
+            log_level: LevelFilter::Info,
+            credential_store: "goliath_credentials.json".into(),
+            tls: TlsConfig::default(),
+            registration: RegistrationConfig::default(),
+            sessions: SessionConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+            connections: ConnectionConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            duplicate_ids: DuplicateIdConfig::default(),
+        }
+    }
+}
+
+/// Flags that take precedence over the config file, each can also be set through its environment variable
+#[derive(Debug, Default, Args)]
+pub struct ConfigOverrides {
+    /// Path to the TOML config file, built-in defaults are used if it doesn't exist
+    #[arg(long, global = true, env = "GOLIATH_CONFIG", default_value = DEFAULT_CONFIG)]
+    pub config: PathBuf,
+    #[arg(long, global = true, env = "GOLIATH_BIND_ADDRESS")]
+    pub bind_address: Option<IpAddr>,
+    #[arg(long, global = true, env = "GOLIATH_SERVER_PORT")]
+    pub port: Option<u16>,
+    #[arg(long, global = true, env = "GOLIATH_LOG_LEVEL")]
+    pub log_level: Option<LevelFilter>,
+    #[arg(long, global = true, env = "GOLIATH_CREDENTIAL_STORE")]
+    pub credential_store: Option<PathBuf>,
+    #[arg(long, global = true, env = "GOLIATH_TLS_CERT")]
+    pub tls_cert: Option<PathBuf>,
+    #[arg(long, global = true, env = "GOLIATH_TLS_KEY")]
+    pub tls_key: Option<PathBuf>,
+    #[arg(long, global = true, env = "GOLIATH_NODE_CA_CERT")]
+    pub node_ca_cert: Option<PathBuf>,
+    #[arg(long, global = true, env = "GOLIATH_NODE_CA_KEY")]
+    pub node_ca_key: Option<PathBuf>,
+    /// One of disabled, optional or required
+    #[arg(long, global = true, env = "GOLIATH_CLIENT_AUTH")]
+    pub client_auth: Option<ClientAuth>,
+    /// Key algorithm used when a new key is generated, renewing keeps the existing key
+    #[arg(long, global = true, env = "GOLIATH_TLS_KEY_ALGORITHM", value_enum)]
+    pub key_algorithm: Option<KeyAlgorithm>,
+    /// DNS names or IP addresses nodes use to reach the backend, the first one is also the common name
+    #[arg(
+        long = "san",
+        global = true,
+        env = "GOLIATH_TLS_SAN",
+        value_delimiter = ','
+    )]
+    pub subject_alt_names: Option<Vec<String>>,
+    #[arg(long, global = true, env = "GOLIATH_TLS_VALIDITY_DAYS")]
+    pub validity_days: Option<u32>,
+    #[arg(long, global = true, env = "GOLIATH_REGISTRATION_WINDOW_SECS")]
+    pub registration_window_secs: Option<u64>,
+    #[arg(long, global = true, env = "GOLIATH_ALLOW_LEGACY_AUTH")]
+    pub allow_legacy_auth: Option<bool>,
+    #[arg(long, global = true, env = "GOLIATH_MAX_SESSIONS")]
+    pub max_sessions: Option<usize>,
+    #[arg(long, global = true, env = "GOLIATH_HEARTBEAT_INTERVAL_SECS")]
+    pub heartbeat_interval_secs: Option<u64>,
+    #[arg(long, global = true, env = "GOLIATH_HEARTBEAT_TIMEOUT_SECS")]
+    pub heartbeat_timeout_secs: Option<u64>,
+    #[arg(long, global = true, env = "GOLIATH_HANDSHAKE_TIMEOUT_SECS")]
+    pub handshake_timeout_secs: Option<u64>,
+    #[arg(long, global = true, env = "GOLIATH_MAX_HANDSHAKES")]
+    pub max_handshakes: Option<usize>,
+    #[arg(long, global = true, env = "GOLIATH_SHUTDOWN_GRACE_PERIOD_SECS")]
+    pub shutdown_grace_period_secs: Option<u64>,
+    /// One of reject, replace or allow-multiple
+    #[arg(long, global = true, env = "GOLIATH_DUPLICATE_CLIENTS")]
+    pub duplicate_clients: Option<DuplicateIdPolicy>,
+    /// One of reject, replace or allow-multiple
+    #[arg(long, global = true, env = "GOLIATH_DUPLICATE_VEHICLES")]
+    pub duplicate_vehicles: Option<DuplicateIdPolicy>,
+}
+
+// Overwrites the target only when an override was given
+fn apply<T>(target: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *target = value;
+    }
+}
+
+impl BackendConfig {
+    pub fn load(overrides: ConfigOverrides) -> Result<Self, ConfigError> {
+        let mut config = match fs::read_to_string(&overrides.config) {
+            Ok(contents) => Self::parse(&contents)
+                .map_err(|err| ConfigError::Malformed(overrides.config.clone(), err))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(err) => return Err(ConfigError::Io(overrides.config, err)),
+        };
+
+        config.apply(overrides);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(contents)
+    }
+
+    fn apply(&mut self, overrides: ConfigOverrides) {
+        apply(&mut self.bind_address, overrides.bind_address);
+        apply(&mut self.port, overrides.port);
+        apply(&mut self.log_level, overrides.log_level);
+        apply(&mut self.credential_store, overrides.credential_store);
+        apply(&mut self.tls.cert, overrides.tls_cert);
+        apply(&mut self.tls.key, overrides.tls_key);
+        apply(&mut self.tls.node_ca_cert, overrides.node_ca_cert);
+        apply(&mut self.tls.node_ca_key, overrides.node_ca_key);
+        apply(&mut self.tls.client_auth, overrides.client_auth);
+        apply(&mut self.tls.key_algorithm, overrides.key_algorithm);
+        apply(&mut self.tls.subject_alt_names, overrides.subject_alt_names);
+        apply(&mut self.tls.validity_days, overrides.validity_days);
+        apply(
+            &mut self.registration.window_secs,
+            overrides.registration_window_secs,
+        );
+        apply(
+            &mut self.registration.allow_legacy_auth,
+            overrides.allow_legacy_auth,
+        );
+        apply(
+            &mut self.sessions.max_sessions,
+            overrides.max_sessions.map(Some),
+        );
+        apply(
+            &mut self.heartbeat.interval_secs,
+            overrides.heartbeat_interval_secs,
+        );
+        apply(
+            &mut self.heartbeat.timeout_secs,
+            overrides.heartbeat_timeout_secs,
+        );
+        apply(
+            &mut self.connections.handshake_timeout_secs,
+            overrides.handshake_timeout_secs,
+        );
+        apply(
+            &mut self.connections.max_handshakes,
+            overrides.max_handshakes,
+        );
+        apply(
+            &mut self.shutdown.grace_period_secs,
+            overrides.shutdown_grace_period_secs,
+        );
+        apply(&mut self.duplicate_ids.clients, overrides.duplicate_clients);
+        apply(
+            &mut self.duplicate_ids.vehicles,
+            overrides.duplicate_vehicles,
+        );
+    }
+
+    /// Reports every problem at once, rather than making the operator fix them one restart at a time
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if self.port == 0 {
+            problems.push("port must not be 0".to_string());
+        }
+        if self.registration.window_secs == 0 {
+            problems.push("registration.window_secs must be greater than 0".to_string());
+        }
+        if self.sessions.max_sessions == Some(0) {
+            problems.push("sessions.max_sessions must be greater than 0, or left out".to_string());
+        }
+        if self.heartbeat.interval_secs == 0 {
+            problems.push("heartbeat.interval_secs must be greater than 0".to_string());
+        }
+        if self.heartbeat.timeout_secs <= self.heartbeat.interval_secs {
+            problems.push(format!(
+                "heartbeat.timeout_secs ({}) must be greater than heartbeat.interval_secs ({})",
+                self.heartbeat.timeout_secs, self.heartbeat.interval_secs
+            ));
+        }
+        if self.connections.handshake_timeout_secs == 0 {
+            problems.push("connections.handshake_timeout_secs must be greater than 0".to_string());
+        }
+        if self.connections.max_handshakes == 0 {
+            problems.push("connections.max_handshakes must be greater than 0".to_string());
+        }
+        if self.tls.validity_days == 0 {
+            problems.push("tls.validity_days must be greater than 0".to_string());
+        }
+        if self.tls.subject_alt_names.is_empty()
+            || self
+                .tls
+                .subject_alt_names
+                .iter()
+                .any(|name| name.is_empty())
+        {
+            problems
+                .push("tls.subject_alt_names must hold at least one non-empty name".to_string());
+        }
+
+        let paths = [
+            ("tls.cert", &self.tls.cert),
+            ("tls.key", &self.tls.key),
+            ("tls.node_ca_cert", &self.tls.node_ca_cert),
+            ("tls.node_ca_key", &self.tls.node_ca_key),
+            ("credential_store", &self.credential_store),
+        ];
+        for (i, (name, path)) in paths.iter().enumerate() {
+            for (other_name, other_path) in &paths[i + 1..] {
+                if path == other_path {
+                    problems.push(format!(
+                        "{name} and {other_name} both point to {}",
+                        path.display()
+                    ));
+                }
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    pub fn registration_policy(&self) -> RegistrationPolicy {
+        RegistrationPolicy {
+            window: Duration::from_secs(self.registration.window_secs),
+            allow_legacy_auth: self.registration.allow_legacy_auth,
+        }
+    }
+
+    pub fn heartbeat_policy(&self) -> HeartbeatPolicy {
+        HeartbeatPolicy {
+            interval: Duration::from_secs(self.heartbeat.interval_secs),
+            timeout: Duration::from_secs(self.heartbeat.timeout_secs),
+        }
+    }
+
+    pub fn handshake_policy(&self) -> HandshakePolicy {
+        HandshakePolicy {
+            timeout: Duration::from_secs(self.connections.handshake_timeout_secs),
+            max_in_flight: self.connections.max_handshakes,
+        }
+    }
+
+    pub fn shutdown_grace_period(&self) -> Duration {
+        Duration::from_secs(self.shutdown.grace_period_secs)
+    }
+
+    pub fn session_policy(&self) -> SessionPolicy {
+        SessionPolicy {
+            max_sessions: self.sessions.max_sessions,
+        }
+    }
+
+    pub fn identity_policy(&self) -> IdentityPolicy {
+        IdentityPolicy {
+            clients: self.duplicate_ids.clients,
+            vehicles: self.duplicate_ids.vehicles,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BackendConfig, ConfigError, ConfigOverrides, DuplicateIdPolicy};
+    use std::net::IpAddr;
+
+    #[test]
+    fn test_layered_config() {
+        let mut config = BackendConfig::parse(
+            r#"
+            bind_address = "::"
+            port = 9000
+
+            [heartbeat]
+            interval_secs = 20
+
+            [duplicate_ids]
+            vehicles = "allow-multiple"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.bind_address, "::".parse::<IpAddr>().unwrap());
+        assert_eq!(config.heartbeat.timeout_secs, 15);
+        assert_eq!(
+            config.duplicate_ids.vehicles,
+            DuplicateIdPolicy::AllowMultiple
+        );
+        assert_eq!(config.duplicate_ids.clients, DuplicateIdPolicy::Replace);
+        assert!(!config.registration.allow_legacy_auth);
+
+        config.apply(ConfigOverrides {
+            port: Some(0),
+            allow_legacy_auth: Some(true),
+            ..Default::default()
+        });
+        assert_eq!(config.bind_address, "::".parse::<IpAddr>().unwrap());
+        assert!(config.registration.allow_legacy_auth);
+
+        // Both problems are reported together
+        match config.validate() {
+            Err(ConfigError::Invalid(problems)) => assert_eq!(problems.len(), 2),
+            res => panic!("Expected an invalid config, got {res:?}"),
+        }
+
+        assert!(BackendConfig::parse("prot = 9000").is_err());
+    }
+}
@@ -0,0 +1,424 @@
+use goliath_common::core::{Client, NaiveDb, NodeType, SecretKey, Vehicle};
+use goliath_common::security::derive_node_key;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CredentialStoreError {
+    #[error("Could not access credential store: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Credential store is malformed: {0}")]
+    Malformed(#[from] serde_json::Error),
+    #[error("Credential store holds the plain secret of {0}, which is only allowed while legacy authentication is on")]
+    PlainSecret(String),
+    #[error("Secret key is not valid base64")]
+    InvalidSecretKey,
+    #[error("{0:?} {1} already exists")]
+    AlreadyExists(NodeType, String),
+    #[error("No such {0:?}: {1}")]
+    NotFound(NodeType, String),
+    #[error("Node type {0:?} can't hold credentials")]
+    UnsupportedNodeType(NodeType),
+}
+
+// This is the on-disk layout, sorted so diffs of the file stay readable
+#[derive(Default, Serialize, Deserialize)]
+struct StoreContents {
+    clients: Vec<Client>,
+    vehicles: Vec<Vehicle>,
+}
+
+/// The node registry the backend authenticates against, persisted as a JSON file.
+/// Secrets are never written to disk, only the key derived from them through `derive_node_key`,
+/// unless a node was added as a legacy node, which keeps its secret until it is rotated
+pub struct CredentialStore {
+    path: PathBuf,
+    clients: HashMap<String, Client>,
+    vehicles: HashMap<String, Vehicle>,
+}
+
+impl CredentialStore {
+    /// Loads the store at `path`, a missing file is treated as an empty store
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, CredentialStoreError> {
+        let path = path.into();
+        let contents = match fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice::<StoreContents>(&bytes)?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => StoreContents::default(),
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(Self {
+            path,
+            clients: contents
+                .clients
+                .into_iter()
+                .map(|client| (client.id.clone(), client))
+                .collect(),
+            vehicles: contents
+                .vehicles
+                .into_iter()
+                .map(|vehicle| (vehicle.id.clone(), vehicle))
+                .collect(),
+        })
+    }
+
+    /// Writes to a temporary file first, so a crash can never leave a half written store behind
+    pub fn save(&self) -> Result<(), CredentialStoreError> {
+        let mut contents = StoreContents {
+            clients: self.clients.values().cloned().collect(),
+            vehicles: self.vehicles.values().cloned().collect(),
+        };
+        contents.clients.sort_by(|a, b| a.id.cmp(&b.id));
+        contents.vehicles.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let tmp_path = self.path.with_extension("tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(&tmp_path)?;
+        file.write_all(&serde_json::to_vec_pretty(&contents)?)?;
+        file.sync_all()?;
+        fs::rename(tmp_path, &self.path)?;
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len() + self.vehicles.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, node_type: NodeType, id: &str) -> Result<bool, CredentialStoreError> {
+        match node_type {
+            NodeType::Client => Ok(self.clients.contains_key(id)),
+            NodeType::Vehicle => Ok(self.vehicles.contains_key(id)),
+            NodeType::Unsorted => Err(CredentialStoreError::UnsupportedNodeType(node_type)),
+        }
+    }
+
+    // Inserts, or overwrites, the node's derived key
+    fn set_node(
+        &mut self,
+        node_type: NodeType,
+        id: &str,
+        secret_key: &str,
+    ) -> Result<(), CredentialStoreError> {
+        let secret_key = SecretKey::Derived(
+            derive_node_key(id, secret_key).map_err(|_| CredentialStoreError::InvalidSecretKey)?,
+        );
+        self.set_secret_key(node_type, id, secret_key)
+    }
+
+    fn set_secret_key(
+        &mut self,
+        node_type: NodeType,
+        id: &str,
+        secret_key: SecretKey,
+    ) -> Result<(), CredentialStoreError> {
+        match node_type {
+            NodeType::Client => {
+                self.clients.insert(
+                    id.to_string(),
+                    Client {
+                        id: id.to_string(),
+                        secret_key,
+                    },
+                );
+            }
+            NodeType::Vehicle => {
+                self.vehicles.insert(
+                    id.to_string(),
+                    Vehicle {
+                        id: id.to_string(),
+                        secret_key,
+                    },
+                );
+            }
+            NodeType::Unsorted => {
+                return Err(CredentialStoreError::UnsupportedNodeType(node_type));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Adds a node, `secret_key` is the base64 secret that the node itself is configured with
+    pub fn insert_node(
+        &mut self,
+        node_type: NodeType,
+        id: &str,
+        secret_key: &str,
+    ) -> Result<(), CredentialStoreError> {
+        if self.contains(node_type, id)? {
+            return Err(CredentialStoreError::AlreadyExists(
+                node_type,
+                id.to_string(),
+            ));
+        }
+
+        self.set_node(node_type, id, secret_key)
+    }
+
+    /// Adds a node that predates the challenge-response handshake. The legacy hash can only be
+    /// checked against the secret itself, so that is what gets stored, until the node is rotated
+    pub fn insert_legacy_node(
+        &mut self,
+        node_type: NodeType,
+        id: &str,
+        secret_key: &str,
+    ) -> Result<(), CredentialStoreError> {
+        if self.contains(node_type, id)? {
+            return Err(CredentialStoreError::AlreadyExists(
+                node_type,
+                id.to_string(),
+            ));
+        }
+        derive_node_key(id, secret_key).map_err(|_| CredentialStoreError::InvalidSecretKey)?;
+
+        self.set_secret_key(node_type, id, SecretKey::Plain(secret_key.to_string()))
+    }
+
+    /// Replaces the key of an existing node, its previous secret stops working immediately
+    pub fn rotate_node(
+        &mut self,
+        node_type: NodeType,
+        id: &str,
+        secret_key: &str,
+    ) -> Result<(), CredentialStoreError> {
+        if !self.contains(node_type, id)? {
+            return Err(CredentialStoreError::NotFound(node_type, id.to_string()));
+        }
+
+        self.set_node(node_type, id, secret_key)
+    }
+
+    pub fn remove_node(
+        &mut self,
+        node_type: NodeType,
+        id: &str,
+    ) -> Result<(), CredentialStoreError> {
+        let removed = match node_type {
+            NodeType::Client => self.clients.remove(id).is_some(),
+            NodeType::Vehicle => self.vehicles.remove(id).is_some(),
+            NodeType::Unsorted => false,
+        };
+
+        removed
+            .then_some(())
+            .ok_or_else(|| CredentialStoreError::NotFound(node_type, id.to_string()))
+    }
+
+    /// Fails on the first node that still keeps its plain secret, for backends that don't accept
+    /// legacy authentication and so have no business holding one
+    pub fn require_derived_keys(&self) -> Result<(), CredentialStoreError> {
+        match self.legacy_nodes().first() {
+            Some((node_type, id)) => Err(CredentialStoreError::PlainSecret(format!(
+                "{node_type:?} {id}"
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// The nodes added with `insert_legacy_node` that have not been rotated since, sorted like `nodes`
+    pub fn legacy_nodes(&self) -> Vec<(NodeType, &str)> {
+        self.nodes()
+            .into_iter()
+            .filter(|&(node_type, id)| {
+                let secret_key = match node_type {
+                    NodeType::Client => self.get_client(id).map(|client| &client.secret_key),
+                    NodeType::Vehicle => self.get_vehicle(id).map(|vehicle| &vehicle.secret_key),
+                    NodeType::Unsorted => None,
+                };
+                matches!(secret_key, Some(SecretKey::Plain(_)))
+            })
+            .collect()
+    }
+
+    /// Every registered node, clients first, each group sorted by id
+    pub fn nodes(&self) -> Vec<(NodeType, &str)> {
+        let mut clients = self.clients.keys().map(String::as_str).collect::<Vec<_>>();
+        let mut vehicles = self.vehicles.keys().map(String::as_str).collect::<Vec<_>>();
+        clients.sort_unstable();
+        vehicles.sort_unstable();
+
+        clients
+            .into_iter()
+            .map(|id| (NodeType::Client, id))
+            .chain(vehicles.into_iter().map(|id| (NodeType::Vehicle, id)))
+            .collect()
+    }
+}
+
+impl NaiveDb for CredentialStore {
+    fn get_client(&self, id: &str) -> Option<&Client> {
+        self.clients.get(id)
+    }
+
+    fn get_client_mut(&mut self, id: &str) -> Option<&mut Client> {
+        self.clients.get_mut(id)
+    }
+
+    fn get_vehicle(&self, id: &str) -> Option<&Vehicle> {
+        self.vehicles.get(id)
+    }
+
+    fn get_vehicle_mut(&mut self, id: &str) -> Option<&mut Vehicle> {
+        self.vehicles.get_mut(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CredentialStore, CredentialStoreError};
+    use goliath_common::core::{NaiveDb, NodeType, SecretKey};
+    use goliath_common::protocol::WireEncoding;
+    use goliath_common::security::{
+        generate_registration_hash, generate_registration_mac, AuthScheme, RegistrationError,
+        RegistrationRequest,
+    };
+
+    // "EmilyVehicleSecret", base64 encoded
+    const KEY: &str = "RW1pbHlWZWhpY2xlU2VjcmV0";
+
+    fn registration(key: &str) -> RegistrationRequest {
+        RegistrationRequest {
+            id: "EmilyVehicle".to_string(),
+            timestamp: 1_700_000_000_000,
+            hash: generate_registration_mac(
+                "EmilyVehicle",
+                NodeType::Vehicle,
+                1_700_000_000_000,
+                "nonce",
+                key,
+            )
+            .unwrap(),
+            node_type: NodeType::Vehicle,
+            encoding: WireEncoding::Bincode,
+            auth_scheme: AuthScheme::HmacSha256,
+        }
+    }
+
+    #[test]
+    fn test_persisted_credentials() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("credentials.json");
+
+        let mut store = CredentialStore::load(&path).unwrap();
+        assert!(store.is_empty());
+        store
+            .insert_node(NodeType::Vehicle, "EmilyVehicle", KEY)
+            .unwrap();
+        assert!(store
+            .insert_node(NodeType::Vehicle, "EmilyVehicle", KEY)
+            .is_err());
+        store.save().unwrap();
+
+        let store = CredentialStore::load(&path).unwrap();
+
+        // The secret itself must never end up on disk
+        let vehicle = store.get_vehicle("EmilyVehicle").unwrap();
+        assert!(matches!(&vehicle.secret_key, SecretKey::Derived(key) if key != KEY));
+
+        assert_eq!(
+            registration(KEY).verify_hash(&store, "nonce"),
+            Ok(NodeType::Vehicle)
+        );
+    }
+
+    #[test]
+    fn test_rotated_and_removed_nodes() {
+        // "EmilyVehicleRotated", base64 encoded
+        const ROTATED_KEY: &str = "RW1pbHlWZWhpY2xlUm90YXRlZA==";
+
+        let mut store = CredentialStore::load("/nonexistent/goliath_test.json").unwrap();
+        assert!(matches!(
+            store.rotate_node(NodeType::Vehicle, "EmilyVehicle", ROTATED_KEY),
+            Err(CredentialStoreError::NotFound(..))
+        ));
+        store
+            .insert_node(NodeType::Vehicle, "EmilyVehicle", KEY)
+            .unwrap();
+
+        store
+            .rotate_node(NodeType::Vehicle, "EmilyVehicle", ROTATED_KEY)
+            .unwrap();
+        assert_eq!(
+            registration(KEY).verify_hash(&store, "nonce"),
+            Err(RegistrationError::MismatchedHash)
+        );
+        assert_eq!(
+            registration(ROTATED_KEY).verify_hash(&store, "nonce"),
+            Ok(NodeType::Vehicle)
+        );
+
+        store
+            .remove_node(NodeType::Vehicle, "EmilyVehicle")
+            .unwrap();
+        assert_eq!(
+            registration(ROTATED_KEY).verify_hash(&store, "nonce"),
+            Err(RegistrationError::VehicleNotFound)
+        );
+        assert!(matches!(
+            store.remove_node(NodeType::Vehicle, "EmilyVehicle"),
+            Err(CredentialStoreError::NotFound(..))
+        ));
+    }
+
+    #[test]
+    fn test_legacy_nodes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("credentials.json");
+
+        let mut store = CredentialStore::load(&path).unwrap();
+        store
+            .insert_legacy_node(NodeType::Vehicle, "EmilyVehicle", KEY)
+            .unwrap();
+        store.save().unwrap();
+        let mut store = CredentialStore::load(&path).unwrap();
+        assert_eq!(
+            store.legacy_nodes(),
+            vec![(NodeType::Vehicle, "EmilyVehicle")]
+        );
+        assert!(matches!(
+            store.require_derived_keys(),
+            Err(CredentialStoreError::PlainSecret(_))
+        ));
+
+        // Exactly what a node that predates the challenge sends
+        let legacy_registration = RegistrationRequest {
+            hash: generate_registration_hash("EmilyVehicle", 1_700_000_000_000, KEY).unwrap(),
+            auth_scheme: AuthScheme::LegacySha256,
+            encoding: WireEncoding::Json,
+            ..registration(KEY)
+        };
+        assert_eq!(
+            legacy_registration.verify_hash(&store, "nonce"),
+            Ok(NodeType::Vehicle)
+        );
+        // Updated nodes get in with the same secret
+        assert_eq!(
+            registration(KEY).verify_hash(&store, "nonce"),
+            Ok(NodeType::Vehicle)
+        );
+
+        // Rotating finishes the migration, the plain secret is gone for good
+        store
+            .rotate_node(NodeType::Vehicle, "EmilyVehicle", KEY)
+            .unwrap();
+        assert!(store.legacy_nodes().is_empty());
+        assert!(store.require_derived_keys().is_ok());
+        assert_eq!(
+            legacy_registration.verify_hash(&store, "nonce"),
+            Err(RegistrationError::LegacyAuthDisabled)
+        );
+    }
+}
@@ -0,0 +1,280 @@
+use crate::security::{node_identity, NodeIdentity};
+use goliath_common::ClientConnection;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use thiserror::Error;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore},
+    time::timeout,
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::{
+    self,
+    protocol::{frame::coding::CloseCode, CloseFrame},
+};
+
+#[derive(Copy, Clone, Debug)]
+pub struct HandshakePolicy {
+    /// How long a peer gets to complete both the TLS handshake and the websocket upgrade,
+    /// and then again to answer the registration challenge
+    pub timeout: Duration,
+    /// How many peers may be handshaking or registering at once, connections beyond that are turned away
+    pub max_in_flight: usize,
+}
+
+#[derive(Debug, Error)]
+enum HandshakeError {
+    #[error("TLS handshake failed: {0}")]
+    Tls(std::io::Error),
+    #[error("Websocket upgrade failed: {0}")]
+    WebSocket(#[from] tungstenite::Error),
+    #[error("Handshake timed out")]
+    Timeout,
+}
+
+/// Keeps a connection counted against `HandshakePolicy::max_in_flight` until it is dropped,
+/// which happens once the node has registered or given up
+pub struct HandshakeSlot {
+    _permit: OwnedSemaphorePermit,
+    /// How long the node gets to register
+    pub timeout: Duration,
+    shutting_down: watch::Receiver<bool>,
+}
+
+impl HandshakeSlot {
+    /// Resolves once the listener shuts down, whoever holds the slot should give up on the node then
+    pub async fn shutting_down(&mut self) {
+        if self
+            .shutting_down
+            .wait_for(|shutting_down| *shutting_down)
+            .await
+            .is_err()
+        {
+            // The listener was dropped without shutting down, so it never will
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Tells a node that hasn't registered yet that it won't get to, since the backend is going away
+pub async fn turn_away(mut ws_socket: ClientConnection) {
+    let close_frame = CloseFrame {
+        code: CloseCode::Away,
+        reason: "Backend is shutting down".into(),
+    };
+    ws_socket.close(Some(close_frame)).await.ok();
+}
+
+/// A connection that made it through the TLS handshake and the websocket upgrade, and can register
+pub struct Handshaked {
+    pub ws_socket: ClientConnection,
+    /// Only present, and already verified against the node CA, when client authentication is on
+    pub peer_identity: Option<NodeIdentity>,
+    pub slot: HandshakeSlot,
+}
+
+async fn handshake(
+    stream: TcpStream,
+    tls_acceptor: TlsAcceptor,
+) -> Result<(ClientConnection, Option<NodeIdentity>), HandshakeError> {
+    let tls_stream = tls_acceptor
+        .accept(stream)
+        .await
+        .map_err(HandshakeError::Tls)?;
+    log::trace!("Accepted TLS Stream");
+
+    let peer_identity = tls_stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(|cert| node_identity(cert));
+
+    Ok((
+        tokio_tungstenite::accept_async(tls_stream).await?,
+        peer_identity,
+    ))
+}
+
+/// Accepts connections and runs each handshake in its own task, so a slow peer only holds up itself
+pub struct Listener {
+    tcp_listener: TcpListener,
+    tls_acceptor: TlsAcceptor,
+    policy: HandshakePolicy,
+    handshake_slots: Arc<Semaphore>,
+    handshaked_tx: mpsc::Sender<Handshaked>,
+    handshaked_rx: mpsc::Receiver<Handshaked>,
+    // Every handshake and registration task watches this through its slot
+    shutting_down: watch::Sender<bool>,
+}
+
+impl Listener {
+    pub fn new(
+        tcp_listener: TcpListener,
+        tls_acceptor: TlsAcceptor,
+        policy: HandshakePolicy,
+    ) -> Self {
+        let (handshaked_tx, handshaked_rx) = mpsc::channel(policy.max_in_flight);
+        Self {
+            tcp_listener,
+            tls_acceptor,
+            policy,
+            handshake_slots: Arc::new(Semaphore::new(policy.max_in_flight)),
+            handshaked_tx,
+            handshaked_rx,
+            shutting_down: watch::Sender::new(false),
+        }
+    }
+
+    fn spawn_handshake(&self, stream: TcpStream, addr: SocketAddr) {
+        let Ok(permit) = self.handshake_slots.clone().try_acquire_owned() else {
+            log::warn!(
+                "{} handshakes already in flight, turning {addr} away",
+                self.policy.max_in_flight
+            );
+            return;
+        };
+
+        let tls_acceptor = self.tls_acceptor.clone();
+        let handshaked_tx = self.handshaked_tx.clone();
+        let mut slot = HandshakeSlot {
+            _permit: permit,
+            timeout: self.policy.timeout,
+            shutting_down: self.shutting_down.subscribe(),
+        };
+        tokio::spawn(async move {
+            let result = tokio::select! {
+                result = timeout(slot.timeout, handshake(stream, tls_acceptor)) => {
+                    result.unwrap_or(Err(HandshakeError::Timeout))
+                }
+                // There is no websocket to close yet, so the connection is simply dropped
+                () = slot.shutting_down() => return,
+            };
+
+            match result {
+                Ok((ws_socket, peer_identity)) => {
+                    // Registration is just as easy to stall, so the slot goes along with the connection
+                    let handshaked = Handshaked {
+                        ws_socket,
+                        peer_identity,
+                        slot,
+                    };
+                    handshaked_tx.send(handshaked).await.ok();
+                }
+                Err(err) => log::debug!("{addr}: {err}"),
+            }
+        });
+    }
+
+    /// Waits for the next connection that completed its handshake, this is cancel safe
+    pub async fn next(&mut self) -> Handshaked {
+        loop {
+            tokio::select! {
+                accepted = self.tcp_listener.accept() => match accepted {
+                    Ok((stream, addr)) => {
+                        log::trace!("Received connection from: {addr}");
+                        self.spawn_handshake(stream, addr);
+                    }
+                    Err(err) => log::debug!("{err}"),
+                },
+                // Never closes, since we hold on to a sender ourselves
+                Some(handshaked) = self.handshaked_rx.recv() => return handshaked,
+            }
+        }
+    }
+
+    /// Stops accepting connections, and turns away every node that is still handshaking or
+    /// registering. Waits at most `grace_period` for all of them to be gone
+    pub async fn shut_down(mut self, grace_period: Duration) {
+        drop(self.tcp_listener);
+        self.shutting_down.send_replace(true);
+
+        // Every slot is free again once the last of them has been turned away
+        let all_slots = self.policy.max_in_flight as u32;
+        let in_flight = async {
+            loop {
+                tokio::select! {
+                    _ = self.handshake_slots.acquire_many(all_slots) => return,
+                    // Made it through the handshake, but was never handed out to register
+                    Some(handshaked) = self.handshaked_rx.recv() => {
+                        turn_away(handshaked.ws_socket).await;
+                    }
+                }
+            }
+        };
+        if timeout(grace_period, in_flight).await.is_err() {
+            log::warn!("Not every handshake or registration was abandoned in time");
+        }
+    }
+}
+
+/// Presents a freshly generated certificate, and doesn't ask nodes for one
+#[cfg(test)]
+pub(crate) fn test_acceptor() -> TlsAcceptor {
+    use crate::security::{CertificateOptions, TlsIdentity};
+    use tokio_rustls::rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+        ServerConfig,
+    };
+
+    let TlsIdentity { cert, key } = TlsIdentity::generate(&CertificateOptions::default()).unwrap();
+    let tls_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![CertificateDer::from(cert.0)],
+            PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.0)),
+        )
+        .unwrap();
+
+    TlsAcceptor::from(Arc::new(tls_config))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{test_acceptor, HandshakePolicy, Listener};
+    use std::time::Duration;
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+        time::timeout,
+    };
+
+    // Resolves once the backend has closed the connection
+    async fn closed_within(stream: &mut TcpStream, limit: Duration) -> bool {
+        let mut buf = [0; 1];
+        matches!(
+            timeout(limit, stream.read(&mut buf)).await,
+            Ok(Ok(0) | Err(_))
+        )
+    }
+
+    #[tokio::test]
+    async fn test_stalled_handshakes_are_capped_and_time_out() {
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp_listener.local_addr().unwrap();
+        let mut listener = Listener::new(
+            tcp_listener,
+            test_acceptor(),
+            HandshakePolicy {
+                timeout: Duration::from_millis(300),
+                max_in_flight: 1,
+            },
+        );
+        tokio::spawn(async move {
+            loop {
+                listener.next().await;
+            }
+        });
+
+        // Never says a word, and takes up the only slot
+        let mut stalled = TcpStream::connect(addr).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut turned_away = TcpStream::connect(addr).await.unwrap();
+        assert!(closed_within(&mut turned_away, Duration::from_millis(200)).await);
+
+        assert!(closed_within(&mut stalled, Duration::from_secs(1)).await);
+        // The slot is free again
+        let mut next = TcpStream::connect(addr).await.unwrap();
+        assert!(!closed_within(&mut next, Duration::from_millis(100)).await);
+    }
+}
@@ -1,9 +1,7 @@
+use clap::{Parser, Subcommand};
 use goliath_common::logging;
-use std::{
-    net::{Ipv4Addr, SocketAddrV4},
-    str::FromStr,
-    sync::Arc,
-};
+use log::LevelFilter;
+use std::{net::SocketAddr, sync::Arc};
 use tokio_rustls::{
     rustls::{
         pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
@@ -12,81 +10,220 @@ use tokio_rustls::{
     TlsAcceptor,
 };
 
-pub mod cache_db;
+pub mod admin;
+pub mod config;
+pub mod credential_store;
+pub mod listener;
 pub mod security;
 pub mod server_core;
 
+#[derive(Parser)]
+#[command(version, about = "Goliath tank backend server")]
+struct Cli {
+    #[command(flatten)]
+    config: config::ConfigOverrides,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the nodes in the credential store, a running server picks changes up on restart
+    Admin {
+        #[command(subcommand)]
+        action: admin::AdminAction,
+    },
+    /// Inspect, renew or regenerate the TLS certificate presented to nodes
+    Certificate {
+        #[command(subcommand)]
+        action: admin::certificate::CertificateAction,
+    },
+}
+
 #[tokio::main]
 async fn main() -> Result<(), ()> {
-    logging::setup_logger();
+    let cli = Cli::parse();
+    let config_path = cli.config.config.clone();
+    let config = config::BackendConfig::load(cli.config);
+
+    // Config errors have to be logged too, so fall back to the default level
+    logging::setup_logger_with_level(
+        config
+            .as_ref()
+            .map(|config| config.log_level)
+            .unwrap_or(LevelFilter::Info),
+    );
+    let config = config.map_err(|err| log::error!("{err}"))?;
+    if !config_path.exists() {
+        log::info!(
+            "No config file found at {}, using defaults",
+            config_path.display()
+        );
+    }
+
+    match cli.command {
+        Some(Command::Admin { action }) => {
+            return admin::run(&config.credential_store, action)
+                .map_err(|err| log::error!("{err}"));
+        }
+        Some(Command::Certificate { action }) => {
+            return admin::certificate::run(
+                &config.tls.cert,
+                &config.tls.key,
+                (&config.tls.node_ca_cert, &config.tls.node_ca_key),
+                &config.credential_store,
+                &config.tls.certificate_options(),
+                action,
+            )
+            .map_err(|err| log::error!("{err}"));
+        }
+        None => {}
+    }
 
-    let port = std::env::var("GOLIATH_SERVER_PORT").unwrap_or_else(|_| {
-        log::warn!("No GOLIATH_SERVER_PORT environment variable found, defaulting to 8555");
-        "8555".to_string()
-    });
-    log::info!("Using configuration port: {}", port);
+    let credential_store = credential_store::CredentialStore::load(&config.credential_store)
+        .map_err(|err| log::error!("{err}"))?;
+    if config.registration.allow_legacy_auth {
+        let legacy_nodes = credential_store.legacy_nodes();
+        if !legacy_nodes.is_empty() {
+            log::warn!(
+                "{} nodes still authenticate with the legacy hash, rotate them once they are updated",
+                legacy_nodes.len()
+            );
+        }
+    } else {
+        credential_store
+            .require_derived_keys()
+            .map_err(|err| log::error!("{err}"))?;
+    }
+    if credential_store.is_empty() {
+        log::warn!(
+            "Credential store {} is empty, no node can register",
+            config.credential_store.display()
+        );
+    } else {
+        log::info!(
+            "Loaded {} nodes from {}",
+            credential_store.len(),
+            config.credential_store.display()
+        );
+    }
 
-    let (cert, key) =
-        security::generate_certification_and_keys().map_err(|err| log::error!("{err}"))?;
+    let tls_identity = security::TlsIdentity::load_or_generate(
+        &config.tls.cert,
+        &config.tls.key,
+        &config.tls.certificate_options(),
+    )
+    .map_err(|err| log::error!("{err}"))?;
+    tls_identity
+        .check_expiry()
+        .map_err(|err| log::error!("{err}"))?;
+    log::info!(
+        "Using TLS certificate {} with fingerprint {}",
+        config.tls.cert.display(),
+        tls_identity.fingerprint()
+    );
+    let security::TlsIdentity { cert, key } = tls_identity;
 
-    let config = ServerConfig::builder()
-        .with_no_client_auth()
+    let config_builder = match config.tls.client_auth {
+        security::ClientAuth::Disabled => ServerConfig::builder().with_no_client_auth(),
+        client_auth => {
+            let node_ca = security::NodeCa::load_or_generate(
+                &config.tls.node_ca_cert,
+                &config.tls.node_ca_key,
+            )
+            .map_err(|err| log::error!("{err}"))?;
+            log::info!(
+                "Accepting node certificates issued by {}",
+                config.tls.node_ca_cert.display()
+            );
+
+            ServerConfig::builder().with_client_cert_verifier(
+                node_ca
+                    .client_cert_verifier(client_auth)
+                    .map_err(|err| log::error!("{err}"))?,
+            )
+        }
+    };
+
+    let tls_config = config_builder
         .with_single_cert(
             vec![CertificateDer::from(cert.0)],
             PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.0)),
         )
         .map_err(|err| log::error!("{err}"))?;
-    let tls_acceptor = TlsAcceptor::from(Arc::new(config));
+    let tls_acceptor = TlsAcceptor::from(Arc::new(tls_config));
 
-    let tcp_socket = tokio::net::TcpListener::bind(SocketAddrV4::new(
-        Ipv4Addr::new(0, 0, 0, 0),
-        u16::from_str(&port).map_err(|err| log::error!("{err}"))?,
-    ))
-    .await
-    .map_err(|err| log::error!("{err}"))?;
+    let tcp_socket =
+        tokio::net::TcpListener::bind(SocketAddr::new(config.bind_address, config.port))
+            .await
+            .map_err(|err| log::error!("{err}"))?;
     log::info!(
         "Socket bound and listening on {}",
         tcp_socket.local_addr().expect("Unable to parse address")
     );
 
-    let rt = tokio::runtime::Handle::current();
-
-    let mut server_core = server_core::ServerCore::create();
-    'main_loop: loop {
-        if server_core.update(&rt) {
-            break;
-        }
-
-        let (stream, addr) = match tcp_socket.accept().await {
-            Ok(res) => res,
-            Err(err) => {
-                log::debug!("{err}");
-                continue 'main_loop;
+    let mut listener = listener::Listener::new(tcp_socket, tls_acceptor, config.handshake_policy());
+    let (server_core, mut server_core_task) = server_core::ServerCore::spawn(
+        config.registration_policy(),
+        config.session_policy(),
+        config.identity_policy(),
+        config.heartbeat_policy(),
+        credential_store,
+    );
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            handshaked = listener.next() => {
+                server_core.on_node_connected(handshaked);
             }
-        };
+            _ = &mut server_core_task => {
+                log::error!("Server core stopped unexpectedly");
+                return Err(());
+            }
+            _ = &mut shutdown => break,
+        }
+    }
 
-        log::trace!("Received connection from: {addr}");
+    // No new connections from here on, and nodes that haven't registered yet are turned away.
+    // Whatever did register by then is closed by the server core
+    let grace_period = config.shutdown_grace_period();
+    listener.shut_down(grace_period).await;
+    server_core.shutdown(grace_period).await;
+    if tokio::time::timeout(grace_period, server_core_task)
+        .await
+        .is_err()
+    {
+        log::warn!(
+            "Server core did not stop within {}s",
+            grace_period.as_secs()
+        );
+    }
 
-        let tls_stream = match tls_acceptor.accept(stream).await {
-            Ok(res) => res,
-            Err(err) => {
-                log::debug!("{err}");
-                continue 'main_loop;
-            }
-        };
+    Ok(())
+}
 
-        log::trace!("Accepted TLS Stream");
+// Resolves on Ctrl-C, and on SIGTERM where there is such a thing
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
 
-        let ws_socket = match tokio_tungstenite::accept_async(tls_stream).await {
-            Ok(res) => res,
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
             Err(err) => {
-                log::debug!("{err}");
-                continue 'main_loop;
+                log::warn!("Could not listen for SIGTERM: {err}");
+                tokio::signal::ctrl_c().await.ok();
             }
-        };
-
-        server_core.on_node_connected(ws_socket).await;
+        }
     }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.ok();
 
-    Ok(())
+    log::info!("Received termination signal");
 }
@@ -1,23 +1,107 @@
-use crate::security::{GoliathCert, GoliathPKey};
-use rcgen::{date_time_ymd, Certificate, CertificateParams, DistinguishedName, PKCS_RSA_SHA256};
+use crate::security::{random::random_serial_number, GoliathCert, GoliathPKey};
+use clap::ValueEnum;
+use rcgen::{
+    Certificate, CertificateParams, DistinguishedName, DnType, KeyPair, SanType,
+    PKCS_ECDSA_P256_SHA256, PKCS_ED25519,
+};
 use rsa::{pkcs8::EncodePrivateKey, rand_core::OsRng, RsaPrivateKey};
+use serde::Deserialize;
+use std::net::IpAddr;
+use time::{Duration, OffsetDateTime};
 
-pub fn generate_certification_and_keys() -> Result<(GoliathCert, GoliathPKey), String> {
-    log::info!("Generating a private key");
-    // We generate a keypair
-    let mut rng = OsRng;
-    let pkey = RsaPrivateKey::new(&mut rng, 2048).map_err(|err| err.to_string())?;
-    let pk_der = pkey.to_pkcs8_der().map_err(|err| err.to_string())?;
-    let key_pair = rcgen::KeyPair::try_from(pk_der.as_bytes()).unwrap();
+/// Node certificates are valid for this long, starting from the moment they're issued
+pub const CERTIFICATE_VALIDITY: Duration = Duration::days(365);
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeyAlgorithm {
+    /// RSA 2048, slow to generate and to handshake with, only kept for old verifiers
+    Rsa,
+    /// Cheap handshakes, and understood by every TLS stack we care about
+    #[default]
+    EcdsaP256,
+    /// Cheapest of all, but not every verifier supports it yet
+    Ed25519,
+}
+
+/// What the backend's own certificate looks like, the node CA always issues ECDSA P-256 certificates
+#[derive(Clone, Debug)]
+pub struct CertificateOptions {
+    /// Key algorithm used when a new key is generated, renewing keeps the existing key
+    pub algorithm: KeyAlgorithm,
+    /// DNS names or IP addresses nodes use to reach the backend, the first one is also the common name
+    pub subject_alt_names: Vec<String>,
+    pub validity_days: u32,
+}
+
+impl Default for CertificateOptions {
+    fn default() -> Self {
+        Self {
+            algorithm: KeyAlgorithm::default(),
+            subject_alt_names: vec!["localhost".to_string()],
+            validity_days: 365,
+        }
+    }
+}
+
+fn generate_key_pair(algorithm: KeyAlgorithm) -> Result<KeyPair, String> {
+    match algorithm {
+        KeyAlgorithm::Rsa => {
+            // rcgen can't generate RSA keys by itself
+            let mut rng = OsRng;
+            let pkey = RsaPrivateKey::new(&mut rng, 2048).map_err(|err| err.to_string())?;
+            let pk_der = pkey.to_pkcs8_der().map_err(|err| err.to_string())?;
+            KeyPair::try_from(pk_der.as_bytes()).map_err(|err| err.to_string())
+        }
+        KeyAlgorithm::EcdsaP256 => {
+            KeyPair::generate(&PKCS_ECDSA_P256_SHA256).map_err(|err| err.to_string())
+        }
+        KeyAlgorithm::Ed25519 => KeyPair::generate(&PKCS_ED25519).map_err(|err| err.to_string()),
+    }
+}
+
+pub fn generate_certification_and_keys(
+    options: &CertificateOptions,
+) -> Result<(GoliathCert, GoliathPKey), String> {
+    log::info!("Generating a {:?} private key", options.algorithm);
+    let key_pair = generate_key_pair(options.algorithm)?;
 
     log::info!("Generated keypair");
 
+    certify_key_pair(key_pair, options)
+}
+
+/// Issues a fresh certificate for an existing private key, whatever its algorithm
+pub fn renew_certification(
+    key: &GoliathPKey,
+    options: &CertificateOptions,
+) -> Result<GoliathCert, String> {
+    let key_pair = KeyPair::try_from(key.0.as_slice()).map_err(|err| err.to_string())?;
+    certify_key_pair(key_pair, options).map(|(cert, _)| cert)
+}
+
+fn certify_key_pair(
+    key_pair: KeyPair,
+    options: &CertificateOptions,
+) -> Result<(GoliathCert, GoliathPKey), String> {
     // Dunno why, it impl's Default but I can't construct it destructure-style so I have to manually do it
     let mut params = CertificateParams::default();
-    params.alg = &PKCS_RSA_SHA256;
-    params.not_after = date_time_ymd(2012, 1, 1);
-    params.not_after = date_time_ymd(2026, 1, 1);
+    params.alg = key_pair.algorithm();
+    params.serial_number = Some(random_serial_number());
+    params.not_before = OffsetDateTime::now_utc();
+    params.not_after = params.not_before + Duration::days(options.validity_days.into());
     params.distinguished_name = DistinguishedName::new();
+    if let Some(name) = options.subject_alt_names.first() {
+        params.distinguished_name.push(DnType::CommonName, name);
+    }
+    params.subject_alt_names = options
+        .subject_alt_names
+        .iter()
+        .map(|name| match name.parse::<IpAddr>() {
+            Ok(address) => SanType::IpAddress(address),
+            Err(_) => SanType::DnsName(name.clone()),
+        })
+        .collect();
     params.key_pair = Some(key_pair);
 
     // Generate certificate
@@ -34,18 +118,28 @@ pub fn generate_certification_and_keys() -> Result<(GoliathCert, GoliathPKey), S
 
 #[cfg(test)]
 mod tests {
-    use super::generate_certification_and_keys;
+    use super::{generate_certification_and_keys, CertificateOptions, KeyAlgorithm};
     use tokio_rustls::rustls::{pki_types::PrivateKeyDer, ServerConfig};
 
     #[test]
     fn test_generate_certificates() {
-        let res = generate_certification_and_keys();
-        assert!(res.is_ok());
-
-        let (cert, key) = res.unwrap();
-        assert!(ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(vec![cert.0.into()], PrivateKeyDer::Pkcs8(key.0.into()))
-            .is_ok());
+        for algorithm in [
+            KeyAlgorithm::Rsa,
+            KeyAlgorithm::EcdsaP256,
+            KeyAlgorithm::Ed25519,
+        ] {
+            let res = generate_certification_and_keys(&CertificateOptions {
+                algorithm,
+                subject_alt_names: vec!["localhost".to_string(), "127.0.0.1".to_string()],
+                ..Default::default()
+            });
+            assert!(res.is_ok());
+
+            let (cert, key) = res.unwrap();
+            assert!(ServerConfig::builder()
+                .with_no_client_auth()
+                .with_single_cert(vec![cert.0.into()], PrivateKeyDer::Pkcs8(key.0.into()))
+                .is_ok());
+        }
     }
 }
@@ -1,6 +1,16 @@
 mod certification_generator;
+mod node_ca;
+mod random;
+mod replay_guard;
+mod tls_identity;
 
-pub use certification_generator::generate_certification_and_keys;
+pub use certification_generator::{
+    generate_certification_and_keys, renew_certification, CertificateOptions, KeyAlgorithm,
+};
+pub use node_ca::{node_identity, ClientAuth, NodeCa, NodeIdentity};
+pub use random::{generate_nonce, generate_secret_key};
+pub use replay_guard::ReplayGuard;
+pub use tls_identity::{TlsIdentity, TlsIdentityError};
 
 // This is so you don't get confused and use the wrong one
 pub struct GoliathCert(pub Vec<u8>);
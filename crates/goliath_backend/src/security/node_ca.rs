@@ -0,0 +1,207 @@
+use crate::security::{
+    certification_generator::CERTIFICATE_VALIDITY, random::random_serial_number, GoliathCert,
+    GoliathPKey, TlsIdentity, TlsIdentityError,
+};
+use goliath_common::core::NodeType;
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType,
+    ExtendedKeyUsagePurpose, IsCa, KeyPair, KeyUsagePurpose, PKCS_ECDSA_P256_SHA256,
+};
+use serde::Deserialize;
+use std::{path::Path, str::FromStr, sync::Arc};
+use time::{Duration, OffsetDateTime};
+use tokio_rustls::rustls::{
+    server::{danger::ClientCertVerifier, WebPkiClientVerifier},
+    RootCertStore,
+};
+
+const CA_VALIDITY: Duration = Duration::days(3650);
+const CA_COMMON_NAME: &str = "Goliath node CA";
+
+/// Whether nodes present certificates issued by the node CA during the TLS handshake
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    /// Every node registers with its secret key
+    #[default]
+    Disabled,
+    /// Nodes holding a certificate register through it, the others with their secret key
+    Optional,
+    /// Nodes without a certificate are turned away during the handshake
+    Required,
+}
+
+impl FromStr for ClientAuth {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "disabled" => Ok(Self::Disabled),
+            "optional" => Ok(Self::Optional),
+            "required" => Ok(Self::Required),
+            _ => Err(format!(
+                "Unknown client authentication mode {value}, expected disabled, optional or required"
+            )),
+        }
+    }
+}
+
+/// Who a node is, as stated by the certificate it presented during the TLS handshake
+#[derive(Clone, Debug, PartialEq)]
+pub struct NodeIdentity {
+    pub id: String,
+    pub node_type: NodeType,
+}
+
+// The organizational unit of a node certificate holds its node type
+fn organizational_unit(node_type: NodeType) -> Option<&'static str> {
+    match node_type {
+        NodeType::Client => Some("client"),
+        NodeType::Vehicle => Some("vehicle"),
+        NodeType::Unsorted => None,
+    }
+}
+
+/// Reads the identity out of a node certificate, which must already have been verified against the CA
+pub fn node_identity(cert: &[u8]) -> Option<NodeIdentity> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let subject = cert.subject();
+    let id = subject.iter_common_name().next()?.as_str().ok()?;
+    let node_type = match subject.iter_organizational_unit().next()?.as_str().ok()? {
+        "client" => NodeType::Client,
+        "vehicle" => NodeType::Vehicle,
+        _ => return None,
+    };
+
+    Some(NodeIdentity {
+        id: id.to_string(),
+        node_type,
+    })
+}
+
+/// The backend-managed CA that issues node certificates, stored like the backend's own identity
+pub struct NodeCa {
+    identity: TlsIdentity,
+    signer: Certificate,
+}
+
+impl NodeCa {
+    pub fn load_or_generate(cert_path: &Path, key_path: &Path) -> Result<Self, TlsIdentityError> {
+        let identity = TlsIdentity::load_or_else(cert_path, key_path, Self::generate)?;
+
+        let key_pair = KeyPair::try_from(identity.key.0.as_slice())
+            .map_err(|err| TlsIdentityError::Malformed(err.to_string()))?;
+        let signer = CertificateParams::from_ca_cert_der(&identity.cert.0, key_pair)
+            .and_then(Certificate::from_params)
+            .map_err(|err| TlsIdentityError::Malformed(err.to_string()))?;
+
+        Ok(Self { identity, signer })
+    }
+
+    fn generate() -> Result<TlsIdentity, TlsIdentityError> {
+        let mut params = CertificateParams::default();
+        params.alg = &PKCS_ECDSA_P256_SHA256;
+        params.serial_number = Some(random_serial_number());
+        params.not_before = OffsetDateTime::now_utc();
+        params.not_after = params.not_before + CA_VALIDITY;
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, CA_COMMON_NAME);
+        params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+        params.key_usages = vec![KeyUsagePurpose::KeyCertSign, KeyUsagePurpose::CrlSign];
+
+        let cert = Certificate::from_params(params)
+            .map_err(|err| TlsIdentityError::Generation(err.to_string()))?;
+        Ok(TlsIdentity {
+            cert: GoliathCert(
+                cert.serialize_der()
+                    .map_err(|err| TlsIdentityError::Generation(err.to_string()))?,
+            ),
+            key: GoliathPKey(cert.serialize_private_key_der()),
+        })
+    }
+
+    /// Only accepts node certificates issued by this CA
+    pub fn client_cert_verifier(
+        &self,
+        client_auth: ClientAuth,
+    ) -> Result<Arc<dyn ClientCertVerifier>, String> {
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(self.identity.cert.0.clone().into())
+            .map_err(|err| err.to_string())?;
+
+        let builder = WebPkiClientVerifier::builder(Arc::new(roots));
+        match client_auth {
+            ClientAuth::Optional => builder.allow_unauthenticated(),
+            _ => builder,
+        }
+        .build()
+        .map_err(|err| err.to_string())
+    }
+
+    /// Issues a certificate whose subject identifies the node, it can then register without a secret key
+    pub fn issue(&self, node_type: NodeType, id: &str) -> Result<TlsIdentity, TlsIdentityError> {
+        let organizational_unit = organizational_unit(node_type).ok_or_else(|| {
+            TlsIdentityError::Generation(format!("{node_type:?} nodes can't hold certificates"))
+        })?;
+
+        let mut params = CertificateParams::default();
+        params.alg = &PKCS_ECDSA_P256_SHA256;
+        params.serial_number = Some(random_serial_number());
+        params.not_before = OffsetDateTime::now_utc();
+        params.not_after = params.not_before + CERTIFICATE_VALIDITY;
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, id);
+        params
+            .distinguished_name
+            .push(DnType::OrganizationalUnitName, organizational_unit);
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+
+        let cert = Certificate::from_params(params)
+            .map_err(|err| TlsIdentityError::Generation(err.to_string()))?;
+        Ok(TlsIdentity {
+            cert: GoliathCert(
+                cert.serialize_der_with_signer(&self.signer)
+                    .map_err(|err| TlsIdentityError::Generation(err.to_string()))?,
+            ),
+            key: GoliathPKey(cert.serialize_private_key_der()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{node_identity, ClientAuth, NodeCa, NodeIdentity};
+    use goliath_common::core::NodeType;
+    use tokio_rustls::rustls::pki_types::UnixTime;
+
+    #[test]
+    fn test_issued_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let (ca_cert_path, ca_key_path) =
+            (dir.path().join("ca.pem"), dir.path().join("ca_key.pem"));
+        let ca = NodeCa::load_or_generate(&ca_cert_path, &ca_key_path).unwrap();
+
+        let issued = ca.issue(NodeType::Vehicle, "Goliath1").unwrap();
+        assert_eq!(
+            node_identity(&issued.cert.0),
+            Some(NodeIdentity {
+                id: "Goliath1".to_string(),
+                node_type: NodeType::Vehicle,
+            })
+        );
+        assert!(ca.issue(NodeType::Unsorted, "Goliath1").is_err());
+
+        // Whatever is issued after reloading the CA must still chain up to it
+        let ca = NodeCa::load_or_generate(&ca_cert_path, &ca_key_path).unwrap();
+        let issued = ca.issue(NodeType::Client, "EmilyClient").unwrap();
+        assert!(ca
+            .client_cert_verifier(ClientAuth::Required)
+            .unwrap()
+            .verify_client_cert(&issued.cert.0.into(), &[], UnixTime::now())
+            .is_ok());
+    }
+}
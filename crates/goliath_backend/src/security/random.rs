@@ -0,0 +1,27 @@
+use base64::Engine;
+use rsa::rand_core::{OsRng, RngCore};
+
+fn random_base64<const N: usize>() -> String {
+    let mut bytes = [0u8; N];
+    OsRng.fill_bytes(&mut bytes);
+    base64::engine::general_purpose::STANDARD.encode(bytes)
+}
+
+/// A fresh, unpredictable nonce for every connection, so registration responses can't be reused
+pub fn generate_nonce() -> String {
+    random_base64::<32>()
+}
+
+/// A new base64 secret for a node, this is what goes in the node's own configuration
+pub fn generate_secret_key() -> String {
+    random_base64::<32>()
+}
+
+/// Unique per certificate, so a renewed certificate never looks like the one it replaces
+pub fn random_serial_number() -> rcgen::SerialNumber {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    // Serial numbers must be positive
+    bytes[0] &= 0x7f;
+    rcgen::SerialNumber::from_slice(&bytes)
+}
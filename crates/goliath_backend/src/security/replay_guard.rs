@@ -0,0 +1,105 @@
+use goliath_common::security::{RegistrationError, RegistrationRequest};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Rejects registrations whose timestamp is outside of the acceptance window,
+/// and remembers every accepted hash for as long as it could still be replayed
+pub struct ReplayGuard {
+    window_millis: u128,
+    seen_hashes: HashMap<String, u128>,
+}
+
+impl ReplayGuard {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window_millis: window.as_millis(),
+            seen_hashes: HashMap::new(),
+        }
+    }
+
+    /// Must be called before verifying the hash, `now` is a unix timestamp in milliseconds
+    pub fn check(
+        &mut self,
+        request: &RegistrationRequest,
+        now: u128,
+    ) -> Result<(), RegistrationError> {
+        // Clocks are never perfectly in sync, so accept a bit of drift in both directions
+        if now.abs_diff(request.timestamp) > self.window_millis {
+            return Err(RegistrationError::StaleTimestamp);
+        }
+
+        // Anything older than the window would be rejected as stale anyway
+        let window_millis = self.window_millis;
+        self.seen_hashes
+            .retain(|_, timestamp| now.abs_diff(*timestamp) <= window_millis);
+
+        if self.seen_hashes.contains_key(&request.hash) {
+            Err(RegistrationError::ReplayedRequest)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Should only be called once the hash was verified, so garbage can't fill the cache
+    pub fn record(&mut self, request: &RegistrationRequest) {
+        self.seen_hashes
+            .insert(request.hash.clone(), request.timestamp);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReplayGuard;
+    use goliath_common::core::NodeType;
+    use goliath_common::protocol::WireEncoding;
+    use goliath_common::security::{AuthScheme, RegistrationError, RegistrationRequest};
+    use std::time::Duration;
+
+    fn request(timestamp: u128) -> RegistrationRequest {
+        RegistrationRequest {
+            id: "EmilyClient".to_string(),
+            timestamp,
+            hash: format!("hash-{timestamp}"),
+            node_type: NodeType::Client,
+            encoding: WireEncoding::Json,
+            auth_scheme: AuthScheme::HmacSha256,
+        }
+    }
+
+    #[test]
+    fn test_expired_registration() {
+        let mut guard = ReplayGuard::new(Duration::from_secs(30));
+        let now = 1_700_000_000_000;
+
+        assert!(guard.check(&request(now - 10_000), now).is_ok());
+        assert!(guard.check(&request(now + 10_000), now).is_ok());
+        assert_eq!(
+            guard.check(&request(now - 31_000), now),
+            Err(RegistrationError::StaleTimestamp)
+        );
+        assert_eq!(
+            guard.check(&request(now + 31_000), now),
+            Err(RegistrationError::StaleTimestamp)
+        );
+    }
+
+    #[test]
+    fn test_replayed_registration() {
+        let mut guard = ReplayGuard::new(Duration::from_secs(30));
+        let now = 1_700_000_000_000;
+        let request = request(now);
+
+        assert!(guard.check(&request, now).is_ok());
+        guard.record(&request);
+        assert_eq!(
+            guard.check(&request, now + 1_000),
+            Err(RegistrationError::ReplayedRequest)
+        );
+
+        // Once the window has passed, the timestamp itself is what gets it rejected
+        assert_eq!(
+            guard.check(&request, now + 31_000),
+            Err(RegistrationError::StaleTimestamp)
+        );
+    }
+}
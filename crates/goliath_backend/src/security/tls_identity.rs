@@ -0,0 +1,195 @@
+use crate::security::{
+    generate_certification_and_keys, renew_certification, CertificateOptions, GoliathCert,
+    GoliathPKey,
+};
+use base64::Engine;
+use goliath_common::security::certificate_fingerprint;
+use std::fs;
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use time::{Duration, OffsetDateTime};
+
+/// How long before the certificate expires we start complaining about it
+pub const EXPIRY_WARNING: Duration = Duration::days(30);
+
+#[derive(Debug, Error)]
+pub enum TlsIdentityError {
+    #[error("Could not access {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("No certificate found in {0}")]
+    NoCertificate(PathBuf),
+    #[error("No PKCS#8 private key found in {0}")]
+    NoPrivateKey(PathBuf),
+    #[error("Only one of {0} and {1} exists, refusing to overwrite it")]
+    Incomplete(PathBuf, PathBuf),
+    #[error("Certificate is malformed: {0}")]
+    Malformed(String),
+    #[error("Could not generate the certificate: {0}")]
+    Generation(String),
+}
+
+/// The certificate and private key the backend presents to every node, stored as two PEM files
+pub struct TlsIdentity {
+    pub cert: GoliathCert,
+    pub key: GoliathPKey,
+}
+
+fn io_error(path: &Path) -> impl FnOnce(std::io::Error) -> TlsIdentityError + '_ {
+    move |err| TlsIdentityError::Io(path.to_path_buf(), err)
+}
+
+fn to_pem(label: &str, der: &[u8]) -> String {
+    let encoded = base64::engine::general_purpose::STANDARD.encode(der);
+    let mut pem = format!("-----BEGIN {label}-----\n");
+    for line in encoded.as_bytes().chunks(64) {
+        pem.push_str(&String::from_utf8_lossy(line));
+        pem.push('\n');
+    }
+    pem.push_str(&format!("-----END {label}-----\n"));
+    pem
+}
+
+fn write_file(path: &Path, contents: &str, private: bool) -> Result<(), TlsIdentityError> {
+    let tmp_path = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if private {
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    }
+    #[cfg(not(unix))]
+    let _ = private;
+
+    let mut file = options.open(&tmp_path).map_err(io_error(path))?;
+    file.write_all(contents.as_bytes())
+        .and_then(|_| file.sync_all())
+        .and_then(|_| fs::rename(&tmp_path, path))
+        .map_err(io_error(path))
+}
+
+impl TlsIdentity {
+    pub fn load(cert_path: &Path, key_path: &Path) -> Result<Self, TlsIdentityError> {
+        let cert_file = fs::File::open(cert_path).map_err(io_error(cert_path))?;
+        let cert = rustls_pemfile::certs(&mut BufReader::new(cert_file))
+            .next()
+            .ok_or_else(|| TlsIdentityError::NoCertificate(cert_path.to_path_buf()))?
+            .map_err(io_error(cert_path))?;
+
+        let key_file = fs::File::open(key_path).map_err(io_error(key_path))?;
+        let key = rustls_pemfile::pkcs8_private_keys(&mut BufReader::new(key_file))
+            .next()
+            .ok_or_else(|| TlsIdentityError::NoPrivateKey(key_path.to_path_buf()))?
+            .map_err(io_error(key_path))?;
+
+        let identity = Self {
+            cert: GoliathCert(cert.to_vec()),
+            key: GoliathPKey(key.secret_pkcs8_der().to_vec()),
+        };
+        // Fail now rather than at the first handshake
+        identity.not_after()?;
+
+        Ok(identity)
+    }
+
+    /// Loads the identity, or generates and saves a new one if neither file exists yet
+    pub fn load_or_generate(
+        cert_path: &Path,
+        key_path: &Path,
+        options: &CertificateOptions,
+    ) -> Result<Self, TlsIdentityError> {
+        Self::load_or_else(cert_path, key_path, || Self::generate(options))
+    }
+
+    /// Like `load_or_generate`, but with a custom way to generate the identity
+    pub fn load_or_else(
+        cert_path: &Path,
+        key_path: &Path,
+        generate: impl FnOnce() -> Result<Self, TlsIdentityError>,
+    ) -> Result<Self, TlsIdentityError> {
+        match (cert_path.exists(), key_path.exists()) {
+            (true, true) => Self::load(cert_path, key_path),
+            (false, false) => {
+                log::info!(
+                    "{} not found, generating it and {}",
+                    cert_path.display(),
+                    key_path.display()
+                );
+                let identity = generate()?;
+                identity.save(cert_path, key_path)?;
+                Ok(identity)
+            }
+            _ => Err(TlsIdentityError::Incomplete(
+                cert_path.to_path_buf(),
+                key_path.to_path_buf(),
+            )),
+        }
+    }
+
+    /// A brand new key and certificate, pinned fingerprints stop matching
+    pub fn generate(options: &CertificateOptions) -> Result<Self, TlsIdentityError> {
+        let (cert, key) =
+            generate_certification_and_keys(options).map_err(TlsIdentityError::Generation)?;
+        Ok(Self { cert, key })
+    }
+
+    /// Keeps the key, but issues a new certificate with a fresh validity period
+    pub fn renew(&mut self, options: &CertificateOptions) -> Result<(), TlsIdentityError> {
+        self.cert =
+            renew_certification(&self.key, options).map_err(TlsIdentityError::Generation)?;
+        Ok(())
+    }
+
+    pub fn save(&self, cert_path: &Path, key_path: &Path) -> Result<(), TlsIdentityError> {
+        write_file(key_path, &to_pem("PRIVATE KEY", &self.key.0), true)?;
+        write_file(cert_path, &to_pem("CERTIFICATE", &self.cert.0), false)
+    }
+
+    pub fn not_after(&self) -> Result<OffsetDateTime, TlsIdentityError> {
+        x509_parser::parse_x509_certificate(&self.cert.0)
+            .map(|(_, cert)| cert.validity().not_after.to_datetime())
+            .map_err(|err| TlsIdentityError::Malformed(err.to_string()))
+    }
+
+    /// What nodes pin through `ServerVerification::Fingerprint`
+    pub fn fingerprint(&self) -> String {
+        certificate_fingerprint(&self.cert.0)
+    }
+
+    /// Logs a warning when the certificate expires soon, and an error if it already has
+    pub fn check_expiry(&self) -> Result<(), TlsIdentityError> {
+        let not_after = self.not_after()?;
+        let remaining = not_after - OffsetDateTime::now_utc();
+        if remaining.is_negative() {
+            log::error!("TLS certificate expired on {not_after}, nodes will refuse to connect, renew it with the certificate command");
+        } else if remaining < EXPIRY_WARNING {
+            log::warn!(
+                "TLS certificate expires in {} days on {not_after}, renew it with the certificate command",
+                remaining.whole_days()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TlsIdentity;
+    use crate::security::CertificateOptions;
+
+    #[test]
+    fn test_persisted_identity() {
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+
+        let options = CertificateOptions::default();
+        let generated = TlsIdentity::load_or_generate(&cert_path, &key_path, &options).unwrap();
+        let mut loaded = TlsIdentity::load_or_generate(&cert_path, &key_path, &options).unwrap();
+        assert_eq!(generated.cert.0, loaded.cert.0);
+        assert_eq!(generated.key.0, loaded.key.0);
+
+        loaded.renew(&options).unwrap();
+        assert_ne!(generated.fingerprint(), loaded.fingerprint());
+        assert_eq!(generated.key.0, loaded.key.0);
+    }
+}
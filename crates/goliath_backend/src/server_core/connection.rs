@@ -0,0 +1,172 @@
+use crate::server_core::types::{
+    ConnectedNode, DisconnectReason, NodeEvent, NodeKey, RegisteredNode,
+};
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use goliath_common::{
+    core::unix_timestamp_millis,
+    protocol::{GoliathMessage, WireEncoding},
+    websocket::{message_channel, HeartbeatPolicy, MessageReceiver},
+    ClientConnection,
+};
+use tokio::{
+    sync::{mpsc, watch},
+    time::{interval_at, Instant},
+};
+use tokio_tungstenite::tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+
+// Only bounds control messages, a node that lets this many pile up has stopped reading
+const OUTGOING_QUEUE: usize = 64;
+
+/// Hands the node's socket over to a reader and a writer task of its own, so a slow or quiet node
+/// never holds up any other
+pub fn spawn(
+    node: RegisteredNode,
+    connection_id: u64,
+    heartbeat: HeartbeatPolicy,
+    events_tx: mpsc::Sender<NodeEvent>,
+    shutting_down: watch::Receiver<bool>,
+) -> ConnectedNode {
+    let (ws_write, ws_read) = node.ws_conn.split();
+    let (outgoing_tx, outgoing_rx) = message_channel(OUTGOING_QUEUE);
+    let (stalled, stalled_rx) = watch::channel(false);
+
+    tokio::spawn(read_frames(
+        ws_read,
+        NodeKey {
+            node_type: node.node_type,
+            id: node.id,
+            connection_id,
+        },
+        heartbeat,
+        events_tx,
+        stalled_rx.clone(),
+    ));
+
+    ConnectedNode {
+        connection_id,
+        outgoing_tx,
+        connected_at: unix_timestamp_millis(),
+        writer: tokio::spawn(write_frames(
+            ws_write,
+            outgoing_rx,
+            node.encoding,
+            heartbeat,
+            shutting_down,
+            stalled_rx,
+        )),
+        stalled,
+    }
+}
+
+// Forwards every message to the server core, until the node goes away or stays silent for too long
+async fn read_frames(
+    mut ws_read: SplitStream<ClientConnection>,
+    node: NodeKey,
+    heartbeat: HeartbeatPolicy,
+    events_tx: mpsc::Sender<NodeEvent>,
+    mut stalled: watch::Receiver<bool>,
+) {
+    let mut timeout_interval = interval_at(Instant::now() + heartbeat.interval, heartbeat.interval);
+    let mut last_seen = Instant::now();
+
+    let reason = loop {
+        tokio::select! {
+            frame = ws_read.next() => {
+                let msg = match frame {
+                    Some(Ok(Message::Close(_))) | None => break DisconnectReason::Closed,
+                    Some(Err(err)) => {
+                        log::debug!("{err}");
+                        break DisconnectReason::Closed;
+                    }
+                    Some(Ok(msg)) => msg,
+                };
+
+                // Pongs count too, they are the answer to our pings
+                last_seen = Instant::now();
+                if !matches!(msg, Message::Text(_) | Message::Binary(_)) {
+                    continue;
+                }
+
+                let event = NodeEvent::Received {
+                    node: node.clone(),
+                    message: GoliathMessage::from_ws_message(&msg),
+                };
+                if events_tx.send(event).await.is_err() {
+                    // The server core is gone, there is nobody left to tell
+                    return;
+                }
+            }
+            _ = timeout_interval.tick() => {
+                if last_seen.elapsed() > heartbeat.timeout {
+                    break DisconnectReason::TimedOut;
+                }
+            }
+            Ok(()) = stalled.changed() => break DisconnectReason::Stalled,
+        }
+    };
+
+    events_tx
+        .send(NodeEvent::Disconnected { node, reason })
+        .await
+        .ok();
+}
+
+// Sends whatever the server core queues, pings on every heartbeat, and closes the connection
+// once the server core has dropped the node and everything queued has been sent. The close frame
+// tells the node whether it was let go of, or whether the whole backend is going away. A stalled
+// node gets no close frame, its socket is just dropped
+async fn write_frames(
+    mut ws_write: SplitSink<ClientConnection, Message>,
+    mut outgoing_rx: MessageReceiver,
+    encoding: WireEncoding,
+    heartbeat: HeartbeatPolicy,
+    shutting_down: watch::Receiver<bool>,
+    mut stalled: watch::Receiver<bool>,
+) {
+    let mut ping_interval = interval_at(Instant::now() + heartbeat.interval, heartbeat.interval);
+
+    loop {
+        let frame = tokio::select! {
+            message = outgoing_rx.recv() => match message {
+                Some(message) => match message.to_ws_message(encoding) {
+                    Ok(frame) => frame,
+                    Err(err) => {
+                        log::error!("{err}");
+                        continue;
+                    }
+                },
+                None => break,
+            },
+            _ = ping_interval.tick() => Message::Ping(Vec::new()),
+        };
+
+        let sent = tokio::select! {
+            sent = ws_write.send(frame) => sent,
+            // The node isn't reading, so neither this frame nor a close frame would get through
+            Ok(()) = stalled.changed() => return,
+        };
+        if let Err(err) = sent {
+            log::debug!("{err}");
+            return;
+        }
+    }
+
+    let close_frame = if *shutting_down.borrow() {
+        CloseFrame {
+            code: CloseCode::Away,
+            reason: "Backend is shutting down".into(),
+        }
+    } else {
+        CloseFrame {
+            code: CloseCode::Normal,
+            reason: "".into(),
+        }
+    };
+    ws_write.send(Message::Close(Some(close_frame))).await.ok();
+}
@@ -1,153 +1,1230 @@
+mod connection;
+mod relay;
+mod roster;
+mod sessions;
 mod types;
-mod unsorted_nodes;
 
-use crate::cache_db::CacheDb;
-use crate::server_core::types::{IdentifiedNode, IdentifiedNodeList, RegistrationTypeResponse};
-use goliath_common::{core::NodeType, security::RegistrationRequest, ClientConnection};
+use crate::credential_store::CredentialStore;
+use crate::listener::{turn_away, Handshaked};
+use crate::security::{generate_nonce, NodeIdentity, ReplayGuard};
+use crate::server_core::roster::RosterTracker;
+use crate::server_core::sessions::SessionRegistry;
+use crate::server_core::types::{
+    ConnectedNode, DisconnectReason, NodeEvent, RegisteredNode, RegistrationContext,
+};
+
+pub use crate::server_core::types::{
+    DuplicateIdPolicy, IdentityPolicy, RegistrationPolicy, SessionPolicy,
+};
+use futures_util::{future::join_all, SinkExt};
+use goliath_common::{
+    core::{unix_timestamp_millis, NaiveDb, NodeType},
+    protocol::{GoliathMessage, ShutdownNotice, WireEncoding},
+    security::{
+        AuthScheme, RegistrationChallenge, RegistrationError, RegistrationRequest,
+        RegistrationResponse,
+    },
+    websocket::HeartbeatPolicy,
+    ClientConnection,
+};
 use std::collections::HashMap;
-use std::{sync::Arc, thread, time::Duration};
-use tokio::{runtime::Handle, sync as TokioSync};
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    sync::{mpsc, watch, Mutex},
+    task::JoinHandle,
+    time::timeout,
+};
 use tokio_stream::StreamExt;
 use tokio_tungstenite::tungstenite::Message;
 
-fn internal_thread(
-    kill_switch_rx: TokioSync::oneshot::Receiver<()>,
-    available_vehicles: IdentifiedNodeList,
-    available_clients: IdentifiedNodeList,
-) {
-    let rt = tokio::runtime::Builder::new_current_thread()
-        .enable_time()
-        .build()
-        .expect("Could not construct tokio runtime");
+/// What the server core can be asked to do, by its handle and by the registration tasks
+enum Command {
+    Register(Box<RegisteredNode>),
+    /// Every node is told, then given up to `grace_period` for its connection to be closed properly
+    Shutdown {
+        grace_period: Duration,
+    },
+}
 
-    rt.spawn({
-        async move {
-            for (id, ws_conn) in available_clients.lock().await.iter_mut() {
-                if let Ok(Some(Message::Text(msg))) = ws_conn.try_next().await {}
+async fn verify_registration(
+    registration_message: &RegistrationRequest,
+    nonce: &str,
+    peer_identity: Option<&NodeIdentity>,
+    registration_context: &RegistrationContext,
+) -> Result<NodeType, RegistrationError> {
+    // A certificate settles who the node is, whatever else it sends has to agree with it
+    match peer_identity {
+        Some(peer_identity) => {
+            if peer_identity.id != registration_message.id
+                || peer_identity.node_type != registration_message.node_type
+            {
+                return Err(RegistrationError::CertificateMismatch);
+            }
+            if registration_message.auth_scheme == AuthScheme::ClientCertificate {
+                // A certificate stays valid after its node is revoked, the store entry doesn't
+                let credential_store = registration_context.credential_store.lock().await;
+                return match peer_identity.node_type {
+                    NodeType::Client => credential_store
+                        .get_client(&peer_identity.id)
+                        .map(|_| NodeType::Client)
+                        .ok_or(RegistrationError::ClientNotFound),
+                    NodeType::Vehicle => credential_store
+                        .get_vehicle(&peer_identity.id)
+                        .map(|_| NodeType::Vehicle)
+                        .ok_or(RegistrationError::VehicleNotFound),
+                    NodeType::Unsorted => Err(RegistrationError::UnknownNodeType),
+                };
             }
-
-            tokio::time::sleep(Duration::from_millis(10)).await;
         }
-    });
+        None if registration_message.auth_scheme == AuthScheme::ClientCertificate => {
+            return Err(RegistrationError::MissingCertificate);
+        }
+        None => {}
+    }
 
-    rt.spawn(async move {
-        for (id, ws_conn) in available_vehicles.lock().await.iter_mut() {
-            if let Ok(Some(Message::Text(msg))) = ws_conn.try_next().await {}
+    if registration_message.auth_scheme == AuthScheme::LegacySha256 {
+        if !registration_context.policy.allow_legacy_auth {
+            return Err(RegistrationError::LegacyAuthDisabled);
         }
+        log::warn!(
+            "{} registered using legacy authentication, it should be updated",
+            registration_message.id
+        );
+    }
+
+    // Held until the hash is recorded, so two copies of the same request can't race each other
+    let mut replay_guard = registration_context.replay_guard.lock().await;
+    replay_guard.check(registration_message, unix_timestamp_millis())?;
+
+    let node_type = registration_message
+        .verify_hash(&*registration_context.credential_store.lock().await, nonce)?;
+    replay_guard.record(registration_message);
 
-        tokio::time::sleep(Duration::from_millis(10)).await;
-    });
-    rt.block_on(kill_switch_rx).ok();
+    Ok(node_type)
+}
+
+// Nodes that predate the protocol envelope send a bare JSON request as soon as they connect,
+// without waiting for the challenge. Left without a scheme it counts as legacy, and anything
+// claiming a newer one has no business being sent this way
+fn legacy_registration(msg: &Message) -> Option<RegistrationRequest> {
+    let Message::Text(text) = msg else {
+        return None;
+    };
+    serde_json::from_str::<RegistrationRequest>(text)
+        .ok()
+        .filter(|registration_message| registration_message.auth_scheme == AuthScheme::LegacySha256)
+}
+
+// Challenges the node, and waits for it to answer with its registration
+async fn receive_registration(
+    ws_conn: &mut ClientConnection,
+    nonce: &str,
+    allow_legacy_auth: bool,
+) -> Option<RegistrationRequest> {
+    // We don't know which encoding the node prefers yet, but every node can decode JSON
+    if let Ok(ws_message) = GoliathMessage::Challenge(RegistrationChallenge {
+        nonce: nonce.to_string(),
+    })
+    .to_ws_message(WireEncoding::Json)
+    .map_err(|err| log::error!("{err}"))
+    {
+        ws_conn.send(ws_message).await.ok();
+    }
+
+    match ws_conn.try_next().await {
+        Ok(Some(msg)) => match GoliathMessage::from_ws_message(&msg) {
+            Ok(GoliathMessage::Registration(registration_message)) => Some(registration_message),
+            Err(_) if allow_legacy_auth => legacy_registration(&msg),
+            _ => None,
+        },
+        _ => None,
+    }
 }
 
 async fn registration_task(
-    mut ws_conn: ClientConnection,
-    unsorted_nodes_tx: TokioSync::mpsc::Sender<RegistrationTypeResponse>,
+    handshaked: Handshaked,
+    commands_tx: mpsc::Sender<Command>,
+    registration_context: RegistrationContext,
 ) {
-    if let Ok(Some(Message::Text(msg))) = ws_conn.try_next().await {
-        if let Ok(registration_message) = serde_json::from_str::<RegistrationRequest>(&msg) {
-            if let Ok(node_type) =
-                registration_message.verify_hash(Arc::new(TokioSync::Mutex::new(CacheDb::new())))
+    // The slot is held until the task ends, so stalled registrations count against the handshake limit
+    let Handshaked {
+        ws_socket: mut ws_conn,
+        peer_identity,
+        mut slot,
+    } = handshaked;
+
+    let nonce = generate_nonce();
+    let registration_timeout = slot.timeout;
+    let received = tokio::select! {
+        received = timeout(
+            registration_timeout,
+            receive_registration(
+                &mut ws_conn,
+                &nonce,
+                registration_context.policy.allow_legacy_auth,
+            ),
+        ) => received,
+        () = slot.shutting_down() => {
+            turn_away(ws_conn).await;
+            return;
+        }
+    };
+    let Ok(registration_message) = received else {
+        log::debug!(
+            "Node did not register within {:.1}s, dropping it",
+            registration_timeout.as_secs_f32()
+        );
+        return;
+    };
+
+    // Whatever the outcome, the node hears back in the encoding it asked for
+    let encoding = registration_message
+        .as_ref()
+        .map(|registration_message| registration_message.encoding)
+        .unwrap_or_default();
+
+    let result = match registration_message {
+        Some(registration_message) => verify_registration(
+            &registration_message,
+            &nonce,
+            peer_identity.as_ref(),
+            &registration_context,
+        )
+        .await
+        .map(|node_type| (registration_message.id, node_type)),
+        None => Err(RegistrationError::InvalidRequest),
+    };
+
+    // Accepted nodes hear back from the server core, which still has to find them a place
+    let (id, node_type) = match result {
+        Ok(registered) => registered,
+        Err(err) => {
+            log::debug!("Rejected node registration: {err}");
+            if let Ok(ws_message) =
+                GoliathMessage::RegistrationResponse(RegistrationResponse::rejected(err))
+                    .to_ws_message(encoding)
+                    .map_err(|err| log::error!("{err}"))
             {
-                unsorted_nodes_tx
-                    .try_send(RegistrationTypeResponse {
-                        id: registration_message.id,
-                        node_type,
-                        ws_conn,
-                    })
-                    .ok();
-                return;
+                ws_conn.send(ws_message).await.ok();
             }
+            ws_conn.close(None).await.ok();
+            return;
         }
+    };
+    // Waits for room rather than losing a node that made it through verification
+    let registered = Command::Register(Box::new(RegisteredNode {
+        id,
+        node_type,
+        ws_conn,
+        encoding,
+    }));
+    if let Err(mpsc::error::SendError(Command::Register(node))) = commands_tx.send(registered).await
+    {
+        log::error!(
+            "Server core is gone, dropping {:?} {}",
+            node.node_type,
+            node.id
+        );
     }
-
-    unsorted_nodes_tx
-        .send(RegistrationTypeResponse {
-            id: "".to_string(),
-            node_type: NodeType::Unsorted,
-            ws_conn,
-        })
-        .await
-        .ok();
 }
 
+/// Owns every registered node and the sessions between them. It only ever runs as a task of its
+/// own, everything else talks to it through a `ServerCoreHandle`
 pub struct ServerCore {
-    available_vehicles: IdentifiedNodeList,
-    available_clients: IdentifiedNodeList,
-    active_sessions: HashMap<String, (IdentifiedNode, IdentifiedNode)>,
-    #[allow(clippy::type_complexity)]
-    node_registration_channel: (
-        TokioSync::mpsc::Sender<RegistrationTypeResponse>,
-        TokioSync::mpsc::Receiver<RegistrationTypeResponse>,
-    ),
-    thread_handle: Option<(TokioSync::oneshot::Sender<()>, thread::JoinHandle<()>)>,
+    heartbeat_policy: HeartbeatPolicy,
+    identity_policy: IdentityPolicy,
+    vehicles: HashMap<String, ConnectedNode>,
+    clients: HashMap<String, ConnectedNode>,
+    sessions: SessionRegistry,
+    roster_tracker: RosterTracker,
+    next_connection_id: u64,
+    // Handed to the reader task of every node, the server core holds on to both ends
+    events_tx: mpsc::Sender<NodeEvent>,
+    // Lets the writer tasks pick the right close frame
+    shutting_down: watch::Sender<bool>,
 }
 
 impl ServerCore {
-    pub fn create() -> Self {
-        let available_vehicles = Arc::new(TokioSync::Mutex::new(HashMap::new()));
-        let available_clients = Arc::new(TokioSync::Mutex::new(HashMap::new()));
-
-        let (kill_switch_tx, kill_switch_rx) = TokioSync::oneshot::channel();
-        let join_handle = thread::Builder::new()
-            .name("WS Handler Thread".to_string())
-            .spawn({
-                let available_vehicles = available_vehicles.clone();
-                let available_clients = available_clients.clone();
-                move || internal_thread(kill_switch_rx, available_vehicles, available_clients)
-            })
-            .expect("Could not launch WS Handler Thread");
+    /// The server core keeps running until it is told to shut down, awaiting the returned task
+    /// waits for it to have closed every connection
+    pub fn spawn(
+        registration_policy: RegistrationPolicy,
+        session_policy: SessionPolicy,
+        identity_policy: IdentityPolicy,
+        heartbeat_policy: HeartbeatPolicy,
+        credential_store: CredentialStore,
+    ) -> (ServerCoreHandle, JoinHandle<()>) {
+        let (commands_tx, commands_rx) = mpsc::channel(128);
+        let (events_tx, events_rx) = mpsc::channel(1024);
+        let server_core = Self {
+            heartbeat_policy,
+            identity_policy,
+            vehicles: HashMap::new(),
+            clients: HashMap::new(),
+            sessions: SessionRegistry::with_limit(session_policy.max_sessions),
+            roster_tracker: RosterTracker::default(),
+            next_connection_id: 0,
+            events_tx,
+            shutting_down: watch::Sender::new(false),
+        };
+
+        let handle = ServerCoreHandle {
+            commands_tx,
+            registration_context: RegistrationContext {
+                policy: registration_policy,
+                replay_guard: Arc::new(Mutex::new(ReplayGuard::new(registration_policy.window))),
+                credential_store: Arc::new(Mutex::new(credential_store)),
+            },
+        };
+
+        (
+            handle,
+            tokio::spawn(server_core.run(commands_rx, events_rx)),
+        )
+    }
+
+    async fn run(
+        mut self,
+        mut commands_rx: mpsc::Receiver<Command>,
+        mut events_rx: mpsc::Receiver<NodeEvent>,
+    ) {
+        let grace_period = loop {
+            tokio::select! {
+                command = commands_rx.recv() => match command {
+                    Some(Command::Register(node)) => self.register(*node),
+                    Some(Command::Shutdown { grace_period }) => break grace_period,
+                    // Every handle is gone, so nothing could ever tell us to stop, and nobody waits for us
+                    None => break Duration::ZERO,
+                },
+                // Never closes, since we hold on to a sender ourselves
+                Some(event) = events_rx.recv() => self.on_event(event),
+            }
+        };
+
+        self.shut_down(grace_period).await;
+    }
+
+    fn register(&mut self, mut node: RegisteredNode) {
+        let connection_id = self.next_connection_id;
+        self.next_connection_id += 1;
+        let (nodes, peers) = match node.node_type {
+            NodeType::Client => (&mut self.clients, &self.vehicles),
+            NodeType::Vehicle => (&mut self.vehicles, &self.clients),
+            NodeType::Unsorted => return,
+        };
+
+        let mut response = RegistrationResponse::accepted();
+        if nodes.contains_key(&node.id) {
+            match self.identity_policy.duplicates(node.node_type) {
+                DuplicateIdPolicy::Reject => {
+                    log::warn!(
+                        "{:?} {} is already connected, rejecting the new connection",
+                        node.node_type,
+                        node.id
+                    );
+                    // Only lives long enough for its writer to deliver the response, anything its
+                    // reader reports is dropped as coming from a stale connection
+                    let rejected = connection::spawn(
+                        node,
+                        connection_id,
+                        self.heartbeat_policy,
+                        self.events_tx.clone(),
+                        self.shutting_down.subscribe(),
+                    );
+                    rejected
+                        .send(&GoliathMessage::RegistrationResponse(
+                            RegistrationResponse::rejected(RegistrationError::DuplicateId),
+                        ))
+                        .ok();
+                    return;
+                }
+                DuplicateIdPolicy::Replace => {
+                    log::warn!(
+                        "{:?} {} registered again, replacing its previous connection",
+                        node.node_type,
+                        node.id
+                    );
+                    if let Some(displaced) = nodes.remove(&node.id) {
+                        relay::end_session(
+                            node.node_type,
+                            &node.id,
+                            "Peer was replaced by a new connection",
+                            Some(&displaced),
+                            peers,
+                            &mut self.sessions,
+                        );
+                        displaced
+                            .send(&GoliathMessage::RegistrationResponse(
+                                RegistrationResponse::rejected(RegistrationError::Displaced),
+                            ))
+                            .ok();
+                    }
+                }
+                DuplicateIdPolicy::AllowMultiple => {
+                    let mut suffix = 2;
+                    while nodes.contains_key(&format!("{}#{suffix}", node.id)) {
+                        suffix += 1;
+                    }
+                    node.id = format!("{}#{suffix}", node.id);
+                    response = RegistrationResponse::accepted_as(&node.id);
+                }
+            }
+        }
+
+        log::info!("{:?} {} registered", node.node_type, node.id);
+        let id = node.id.clone();
+        let connected = connection::spawn(
+            node,
+            connection_id,
+            self.heartbeat_policy,
+            self.events_tx.clone(),
+            self.shutting_down.subscribe(),
+        );
+        connected
+            .send(&GoliathMessage::RegistrationResponse(response))
+            .ok();
+        nodes.insert(id, connected);
+        self.push_roster_changes();
+    }
 
-        Self {
-            node_registration_channel: TokioSync::mpsc::channel(128),
-            available_vehicles,
-            available_clients,
-            active_sessions: HashMap::new(),
-            thread_handle: Some((kill_switch_tx, join_handle)),
+    fn on_event(&mut self, event: NodeEvent) {
+        let (NodeEvent::Received { node: key, .. } | NodeEvent::Disconnected { node: key, .. }) =
+            &event;
+        let key = key.clone();
+        let (nodes, peers) = match key.node_type {
+            NodeType::Client => (&mut self.clients, &mut self.vehicles),
+            NodeType::Vehicle => (&mut self.vehicles, &mut self.clients),
+            NodeType::Unsorted => return,
+        };
+        // Whatever is still in flight from a connection that has since been replaced is dropped
+        if nodes.get(&key.id).map(|node| node.connection_id) != Some(key.connection_id) {
+            return;
         }
+
+        match event {
+            NodeEvent::Received {
+                message: Ok(message),
+                ..
+            } => {
+                // Relayed drive commands and telemetry never change the roster, sessions do
+                let changes_roster = matches!(message, GoliathMessage::Session(_));
+                relay::handle_message(
+                    key.node_type,
+                    &key.id,
+                    &nodes[&key.id],
+                    message,
+                    peers,
+                    &mut self.sessions,
+                );
+                if changes_roster {
+                    self.push_roster_changes();
+                }
+            }
+            NodeEvent::Received {
+                message: Err(err), ..
+            } => {
+                nodes[&key.id].send(&GoliathMessage::error(err)).ok();
+            }
+            NodeEvent::Disconnected { reason, .. } => {
+                nodes.remove(&key.id);
+                let reason = match reason {
+                    DisconnectReason::Closed => {
+                        log::info!("{:?} {} disconnected", key.node_type, key.id);
+                        "Peer disconnected"
+                    }
+                    DisconnectReason::TimedOut => {
+                        log::info!("{:?} {} timed out", key.node_type, key.id);
+                        "Peer timed out"
+                    }
+                    DisconnectReason::Stalled => {
+                        log::warn!("{:?} {} stopped reading", key.node_type, key.id);
+                        "Peer stopped reading"
+                    }
+                };
+                relay::end_session(
+                    key.node_type,
+                    &key.id,
+                    reason,
+                    None,
+                    peers,
+                    &mut self.sessions,
+                );
+                self.push_roster_changes();
+            }
+        }
+    }
+
+    fn push_roster_changes(&mut self) {
+        let roster = roster::vehicle_roster(&self.vehicles, &self.sessions);
+        for change in self.roster_tracker.update(roster) {
+            let message = GoliathMessage::Discovery(change);
+            for client in self.clients.values() {
+                client.send(&message).ok();
+            }
+        }
+    }
+
+    async fn shut_down(&mut self, grace_period: Duration) {
+        log::info!(
+            "Shutting down, closing {} connections",
+            self.clients.len() + self.vehicles.len()
+        );
+        self.shutting_down.send_replace(true);
+        let notice = GoliathMessage::Shutdown(ShutdownNotice {
+            reason: "Backend is shutting down".to_string(),
+        });
+        for node in self.clients.values().chain(self.vehicles.values()) {
+            node.send(&notice).ok();
+        }
+
+        let writers = self
+            .clients
+            .drain()
+            .chain(self.vehicles.drain())
+            .map(|(_, node)| node.writer)
+            .collect::<Vec<_>>();
+
+        // Each writer sends the notice, then closes its connection once it notices its node is gone
+        if timeout(grace_period, join_all(writers)).await.is_err() {
+            log::warn!("Not every connection closed in time");
+        }
+        log::info!("Server core stopped");
     }
+}
 
-    pub async fn on_node_connected(&mut self, ws_socket: ClientConnection) {
+/// Cheap to clone, every clone talks to the same server core
+#[derive(Clone)]
+pub struct ServerCoreHandle {
+    commands_tx: mpsc::Sender<Command>,
+    registration_context: RegistrationContext,
+}
+
+impl ServerCoreHandle {
+    /// Registers the node in a task of its own, the server core only hears about it once it succeeded
+    pub fn on_node_connected(&self, handshaked: Handshaked) {
         tokio::spawn(registration_task(
-            ws_socket,
-            self.node_registration_channel.0.clone(),
+            handshaked,
+            self.commands_tx.clone(),
+            self.registration_context.clone(),
         ));
     }
 
-    // If this function returns true, then we need to shut down the server cause shit has gone bad
-    pub fn update(&mut self, rt: &Handle) -> bool {
-        while let Ok(mut response) = self.node_registration_channel.1.try_recv() {
-            match response.node_type {
-                NodeType::Unsorted => {
-                    rt.block_on(async {
-                        response.ws_conn.close(None).await.ok();
-                    });
+    /// Asks the server core to tell every node, close every connection and stop.
+    /// Await its task to know when it has, which takes at most `grace_period`
+    pub async fn shutdown(&self, grace_period: Duration) {
+        self.commands_tx
+            .send(Command::Shutdown { grace_period })
+            .await
+            .ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        verify_registration, DuplicateIdPolicy, IdentityPolicy, RegistrationContext,
+        RegistrationPolicy, ServerCore, ServerCoreHandle, SessionPolicy,
+    };
+    use crate::credential_store::CredentialStore;
+    use crate::listener::{test_acceptor, HandshakePolicy, Listener};
+    use crate::security::{generate_secret_key, NodeIdentity, ReplayGuard};
+    use futures_util::{SinkExt, StreamExt};
+    use goliath_common::{
+        core::{unix_timestamp_millis, NodeType},
+        protocol::{Discovery, DriveCommand, GoliathMessage, SessionControl, WireEncoding},
+        security::{
+            generate_registration_hash, generate_registration_mac, AuthScheme, RegistrationError,
+            RegistrationRequest, RegistrationResponse, ServerVerification,
+        },
+        websocket::{
+            Backoff, ConnectionOptions, ConnectionState, HeartbeatPolicy, NodeCredentials,
+            ReconnectingConnection,
+        },
+    };
+    use std::{net::SocketAddr, sync::Arc, time::Duration};
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+        sync::{oneshot, Mutex},
+        task::JoinHandle,
+        time::timeout,
+    };
+    use tokio_rustls::{client::TlsStream, rustls::pki_types::ServerName, TlsConnector};
+    use tokio_tungstenite::{
+        client_async,
+        tungstenite::{
+            protocol::{frame::coding::CloseCode, CloseFrame},
+            Message,
+        },
+        WebSocketStream,
+    };
+
+    // A node connection with nothing behind it, frames are only read and pings only answered
+    // while the test polls it
+    type RawConnection = WebSocketStream<TlsStream<TcpStream>>;
+
+    const LIMIT: Duration = Duration::from_secs(5);
+
+    struct TestBackend {
+        addr: SocketAddr,
+        server_core: ServerCoreHandle,
+        server_core_task: JoinHandle<()>,
+        stop_listening: oneshot::Sender<()>,
+        listener_task: JoinHandle<()>,
+        keys: Vec<(NodeType, String, String)>,
+    }
+
+    // The policies a test may want to tighten, everything else is the same for every test
+    struct TestPolicies {
+        /// Every node is then added as a legacy node, which can register either way
+        allow_legacy_auth: bool,
+        identity: IdentityPolicy,
+        heartbeat: HeartbeatPolicy,
+        handshake: HandshakePolicy,
+    }
+
+    impl Default for TestPolicies {
+        fn default() -> Self {
+            Self {
+                allow_legacy_auth: false,
+                identity: IdentityPolicy::default(),
+                heartbeat: HeartbeatPolicy::default(),
+                handshake: HandshakePolicy {
+                    timeout: LIMIT,
+                    max_in_flight: 8,
+                },
+            }
+        }
+    }
+
+    async fn start_backend(nodes: &[(NodeType, &str)]) -> TestBackend {
+        start_backend_with(nodes, TestPolicies::default()).await
+    }
+
+    // Runs the backend the way `main` does, minus the config, with every node in `nodes` known to it
+    async fn start_backend_with(nodes: &[(NodeType, &str)], policies: TestPolicies) -> TestBackend {
+        let mut credential_store = CredentialStore::load("/nonexistent/goliath_test.json").unwrap();
+        let keys = nodes
+            .iter()
+            .map(|&(node_type, id)| {
+                let key = generate_secret_key();
+                if policies.allow_legacy_auth {
+                    credential_store
+                        .insert_legacy_node(node_type, id, &key)
+                        .unwrap();
+                } else {
+                    credential_store.insert_node(node_type, id, &key).unwrap();
                 }
-                NodeType::Client => {
-                    self.available_clients
-                        .blocking_lock()
-                        .insert(response.id, response.ws_conn);
+                (node_type, id.to_string(), key)
+            })
+            .collect();
+
+        let (server_core, server_core_task) = ServerCore::spawn(
+            RegistrationPolicy {
+                window: Duration::from_secs(30),
+                allow_legacy_auth: policies.allow_legacy_auth,
+            },
+            SessionPolicy::default(),
+            policies.identity,
+            policies.heartbeat,
+            credential_store,
+        );
+
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp_listener.local_addr().unwrap();
+        let mut listener = Listener::new(tcp_listener, test_acceptor(), policies.handshake);
+        let (stop_listening, mut stop_rx) = oneshot::channel();
+        let listener_task = tokio::spawn({
+            let server_core = server_core.clone();
+            async move {
+                loop {
+                    tokio::select! {
+                        handshaked = listener.next() => server_core.on_node_connected(handshaked),
+                        _ = &mut stop_rx => break,
+                    }
                 }
-                NodeType::Vehicle => {
-                    self.available_vehicles
-                        .blocking_lock()
-                        .insert(response.id, response.ws_conn);
+                listener.shut_down(LIMIT).await;
+            }
+        });
+
+        TestBackend {
+            addr,
+            server_core,
+            server_core_task,
+            stop_listening,
+            listener_task,
+            keys,
+        }
+    }
+
+    impl TestBackend {
+        // In the same order as `main`
+        async fn shut_down(self) {
+            self.stop_listening.send(()).ok();
+            timeout(LIMIT, self.listener_task)
+                .await
+                .expect("Listener did not stop in time")
+                .unwrap();
+            self.server_core.shutdown(LIMIT).await;
+            timeout(LIMIT, self.server_core_task)
+                .await
+                .expect("Server core did not stop in time")
+                .unwrap();
+        }
+
+        async fn connect(&self, id: &str) -> ReconnectingConnection {
+            let connection = self.open(id);
+            wait_for_state(&connection, |state| *state == ConnectionState::Registered).await;
+            connection
+        }
+
+        fn credentials(&self, id: &str) -> (NodeType, &str) {
+            let (node_type, _, key) = self
+                .keys
+                .iter()
+                .find(|(_, node_id, _)| node_id == id)
+                .unwrap();
+            (*node_type, key)
+        }
+
+        // Doesn't wait for the registration to go through, or to be turned down
+        fn open(&self, id: &str) -> ReconnectingConnection {
+            let (node_type, key) = self.credentials(id);
+            ReconnectingConnection::spawn(ConnectionOptions {
+                address: self.addr.to_string(),
+                id: id.to_string(),
+                node_type,
+                credentials: NodeCredentials::SecretKey(key.to_string()),
+                encoding: WireEncoding::Bincode,
+                server_verification: ServerVerification::Insecure,
+                // Quick enough that a node retrying its registration gets through within `LIMIT`
+                backoff: Backoff {
+                    initial: Duration::from_millis(50),
+                    max: Duration::from_millis(200),
+                },
+                heartbeat: HeartbeatPolicy::default(),
+            })
+        }
+
+        // Only gets as far as the websocket upgrade, registering is up to the test
+        async fn upgrade(&self) -> RawConnection {
+            let connector = TlsConnector::from(Arc::new(
+                ServerVerification::Insecure.client_config(None).unwrap(),
+            ));
+            let tls_stream = connector
+                .connect(
+                    ServerName::try_from("localhost").unwrap(),
+                    TcpStream::connect(self.addr).await.unwrap(),
+                )
+                .await
+                .unwrap();
+            let (ws_conn, _) =
+                client_async(format!("wss://localhost:{}", self.addr.port()), tls_stream)
+                    .await
+                    .unwrap();
+            ws_conn
+        }
+
+        // Registers without a `ReconnectingConnection`, so the test decides when the node goes quiet
+        async fn connect_raw(&self, id: &str) -> RawConnection {
+            let (node_type, key) = self.credentials(id);
+            let mut ws_conn = self.upgrade().await;
+            let GoliathMessage::Challenge(challenge) = recv_raw(&mut ws_conn).await else {
+                panic!("Expected a challenge");
+            };
+            let timestamp = unix_timestamp_millis();
+            let registration = GoliathMessage::Registration(RegistrationRequest {
+                id: id.to_string(),
+                timestamp,
+                hash: generate_registration_mac(id, node_type, timestamp, &challenge.nonce, key)
+                    .unwrap(),
+                node_type,
+                encoding: WireEncoding::Json,
+                auth_scheme: AuthScheme::HmacSha256,
+            });
+            ws_conn
+                .send(registration.to_ws_message(WireEncoding::Json).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(
+                recv_raw(&mut ws_conn).await,
+                GoliathMessage::RegistrationResponse(RegistrationResponse::accepted())
+            );
+            ws_conn
+        }
+    }
+
+    // Skips over frames that aren't messages, answering pings along the way
+    async fn recv_raw(ws_conn: &mut RawConnection) -> GoliathMessage {
+        timeout(LIMIT, async {
+            loop {
+                let frame = ws_conn.next().await.unwrap().unwrap();
+                if let Ok(message) = GoliathMessage::from_ws_message(&frame) {
+                    return message;
+                }
+            }
+        })
+        .await
+        .expect("Expected message never arrived")
+    }
+
+    async fn wait_for_state(
+        connection: &ReconnectingConnection,
+        condition: impl Fn(&ConnectionState) -> bool,
+    ) {
+        timeout(LIMIT, connection.watch_state().wait_for(condition))
+            .await
+            .expect("Connection state did not change in time")
+            .unwrap();
+    }
+
+    // Has the client ask for the vehicle, and waits for both to hear the session started
+    async fn start_session(
+        client: &mut ReconnectingConnection,
+        vehicle: &mut ReconnectingConnection,
+        vehicle_id: &str,
+    ) -> (GoliathMessage, GoliathMessage) {
+        client
+            .send(GoliathMessage::Session(SessionControl::Request {
+                vehicle_id: vehicle_id.to_string(),
+            }))
+            .await
+            .unwrap();
+        let is_started = |message: &GoliathMessage| {
+            matches!(
+                message,
+                GoliathMessage::Session(SessionControl::Started { .. })
+            )
+        };
+        (
+            expect_message(client, is_started).await,
+            expect_message(vehicle, is_started).await,
+        )
+    }
+
+    async fn expect_message(
+        connection: &mut ReconnectingConnection,
+        condition: impl Fn(&GoliathMessage) -> bool,
+    ) -> GoliathMessage {
+        timeout(LIMIT, async {
+            loop {
+                match connection.recv().await {
+                    Some(message) if condition(&message) => return message,
+                    Some(_) => {}
+                    None => panic!("Connection gave up"),
                 }
             }
+        })
+        .await
+        .expect("Expected message never arrived")
+    }
+
+    #[tokio::test]
+    async fn test_revoked_certificate_is_rejected() {
+        let mut credential_store = CredentialStore::load("/nonexistent/goliath_test.json").unwrap();
+        credential_store
+            .insert_node(NodeType::Vehicle, "Goliath1", &generate_secret_key())
+            .unwrap();
+        let registration_context = RegistrationContext {
+            policy: RegistrationPolicy {
+                window: Duration::from_secs(30),
+                allow_legacy_auth: false,
+            },
+            replay_guard: Arc::new(Mutex::new(ReplayGuard::new(Duration::from_secs(30)))),
+            credential_store: Arc::new(Mutex::new(credential_store)),
+        };
+        let peer_identity = NodeIdentity {
+            id: "Goliath1".to_string(),
+            node_type: NodeType::Vehicle,
+        };
+        let registration_message = RegistrationRequest {
+            id: "Goliath1".to_string(),
+            timestamp: 0,
+            hash: String::new(),
+            node_type: NodeType::Vehicle,
+            encoding: WireEncoding::default(),
+            auth_scheme: AuthScheme::ClientCertificate,
+        };
+        let verify = || {
+            verify_registration(
+                &registration_message,
+                "nonce",
+                Some(&peer_identity),
+                &registration_context,
+            )
+        };
+
+        assert_eq!(verify().await, Ok(NodeType::Vehicle));
+
+        // The certificate itself is untouched by `admin revoke`, only the store entry goes
+        registration_context
+            .credential_store
+            .lock()
+            .await
+            .remove_node(NodeType::Vehicle, "Goliath1")
+            .unwrap();
+        assert_eq!(verify().await, Err(RegistrationError::VehicleNotFound));
+    }
+
+    #[tokio::test]
+    async fn test_register_and_start_session() {
+        let backend = start_backend(&[
+            (NodeType::Vehicle, "Goliath1"),
+            (NodeType::Client, "Operator"),
+        ])
+        .await;
+        let mut vehicle = backend.connect("Goliath1").await;
+        let mut client = backend.connect("Operator").await;
+
+        client
+            .send(GoliathMessage::Discovery(Discovery::ListVehicles))
+            .await
+            .unwrap();
+        let roster = expect_message(&mut client, |message| {
+            matches!(
+                message,
+                GoliathMessage::Discovery(Discovery::VehicleList(_))
+            )
+        })
+        .await;
+        let GoliathMessage::Discovery(Discovery::VehicleList(vehicles)) = roster else {
+            unreachable!()
+        };
+        assert_eq!(vehicles.len(), 1);
+        assert_eq!(vehicles[0].id, "Goliath1");
+
+        client
+            .send(GoliathMessage::Session(SessionControl::Request {
+                vehicle_id: "Goliath1".to_string(),
+            }))
+            .await
+            .unwrap();
+        for (connection, peer) in [(&mut client, "Goliath1"), (&mut vehicle, "Operator")] {
+            let started = expect_message(connection, |message| {
+                matches!(
+                    message,
+                    GoliathMessage::Session(SessionControl::Started { .. })
+                )
+            })
+            .await;
+            assert!(matches!(
+                started,
+                GoliathMessage::Session(SessionControl::Started { peer_id, .. }) if peer_id == peer
+            ));
         }
 
-        self.thread_handle
-            .as_ref()
-            .map(|(_, join_handle)| join_handle.is_finished())
-            .unwrap_or_default()
+        let command = DriveCommand {
+            left_track: 0.5,
+            right_track: -0.5,
+        };
+        client.send(GoliathMessage::Drive(command)).await.unwrap();
+        let relayed = expect_message(&mut vehicle, |message| {
+            matches!(message, GoliathMessage::Drive(_))
+        })
+        .await;
+        assert_eq!(relayed, GoliathMessage::Drive(command));
+
+        drop(vehicle);
+        let ended = expect_message(&mut client, |message| {
+            matches!(
+                message,
+                GoliathMessage::Session(SessionControl::Ended { .. })
+            )
+        })
+        .await;
+        assert!(matches!(
+            ended,
+            GoliathMessage::Session(SessionControl::Ended { reason, .. }) if reason == "Peer disconnected"
+        ));
     }
-}
 
-impl Drop for ServerCore {
-    fn drop(&mut self) {
-        if let Some((kill_switch_tx, join_handle)) = self.thread_handle.take() {
-            kill_switch_tx.send(()).ok();
-            join_handle.join().ok();
+    #[tokio::test]
+    async fn test_shutdown_notifies_and_closes_connections() {
+        let backend = start_backend(&[(NodeType::Vehicle, "Goliath1")]).await;
+        let mut vehicle = backend.connect("Goliath1").await;
+        // Upgraded, but yet to answer the challenge
+        let mut unregistered = backend.upgrade().await;
+        assert!(matches!(
+            recv_raw(&mut unregistered).await,
+            GoliathMessage::Challenge(_)
+        ));
+
+        backend.shut_down().await;
+        expect_message(&mut vehicle, |message| {
+            matches!(message, GoliathMessage::Shutdown(_))
+        })
+        .await;
+        wait_for_state(&vehicle, |state| *state != ConnectionState::Registered).await;
+        let frame = timeout(LIMIT, unregistered.next()).await.unwrap();
+        assert!(matches!(
+            frame,
+            Some(Ok(Message::Close(Some(CloseFrame {
+                code: CloseCode::Away,
+                ..
+            }))))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_replaced_node_and_its_peer_are_told() {
+        let backend = start_backend(&[
+            (NodeType::Vehicle, "Goliath1"),
+            (NodeType::Client, "Operator"),
+        ])
+        .await;
+        let mut vehicle = backend.connect("Goliath1").await;
+        let mut client = backend.connect("Operator").await;
+        start_session(&mut client, &mut vehicle, "Goliath1").await;
+
+        let _replacement = backend.connect("Goliath1").await;
+        let is_ended = |message: &GoliathMessage| {
+            matches!(
+                message,
+                GoliathMessage::Session(SessionControl::Ended { reason, .. })
+                    if reason == "Peer was replaced by a new connection"
+            )
+        };
+        expect_message(&mut client, is_ended).await;
+        expect_message(&mut vehicle, is_ended).await;
+        // The displaced vehicle gives up, instead of taking its id back
+        wait_for_state(&vehicle, |state| {
+            *state == ConnectionState::Rejected(RegistrationError::Displaced)
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_ids_are_rejected_or_suffixed() {
+        let backend = start_backend_with(
+            &[
+                (NodeType::Vehicle, "Goliath1"),
+                (NodeType::Client, "Operator"),
+            ],
+            TestPolicies {
+                identity: IdentityPolicy {
+                    clients: DuplicateIdPolicy::AllowMultiple,
+                    vehicles: DuplicateIdPolicy::Reject,
+                },
+                ..Default::default()
+            },
+        )
+        .await;
+        let mut vehicle = backend.connect("Goliath1").await;
+        let _client = backend.connect("Operator").await;
+
+        let duplicate = backend.open("Goliath1");
+        let turned_away = RegistrationError::DuplicateId.to_string();
+        wait_for_state(
+            &duplicate,
+            |state| matches!(state, ConnectionState::Lost { reason, .. } if *reason == turned_away),
+        )
+        .await;
+
+        let mut second_client = backend.connect("Operator").await;
+        assert_eq!(second_client.registered_id(), "Operator#2");
+        let (_, started) = start_session(&mut second_client, &mut vehicle, "Goliath1").await;
+        assert!(matches!(
+            started,
+            GoliathMessage::Session(SessionControl::Started { peer_id, .. }) if peer_id == "Operator#2"
+        ));
+        assert_eq!(vehicle.state(), ConnectionState::Registered);
+        assert_ne!(duplicate.state(), ConnectionState::Registered);
+
+        // The duplicate kept trying, and gets the id once the first vehicle is gone
+        drop(vehicle);
+        wait_for_state(&duplicate, |state| *state == ConnectionState::Registered).await;
+    }
+
+    #[tokio::test]
+    async fn test_silent_peer_times_out() {
+        let backend = start_backend_with(
+            &[
+                (NodeType::Vehicle, "Goliath1"),
+                (NodeType::Client, "Operator"),
+            ],
+            TestPolicies {
+                heartbeat: HeartbeatPolicy {
+                    interval: Duration::from_millis(100),
+                    timeout: Duration::from_millis(400),
+                },
+                ..Default::default()
+            },
+        )
+        .await;
+        let mut client = backend.connect("Operator").await;
+        let mut vehicle = backend.connect_raw("Goliath1").await;
+
+        client
+            .send(GoliathMessage::Session(SessionControl::Request {
+                vehicle_id: "Goliath1".to_string(),
+            }))
+            .await
+            .unwrap();
+        loop {
+            if let GoliathMessage::Session(SessionControl::Started { .. }) =
+                recv_raw(&mut vehicle).await
+            {
+                break;
+            }
+        }
+
+        // The vehicle is never read again, so the backend's pings go unanswered
+        expect_message(&mut client, |message| {
+            matches!(
+                message,
+                GoliathMessage::Session(SessionControl::Ended { reason, .. })
+                    if reason == "Peer timed out"
+            )
+        })
+        .await;
+        expect_message(&mut client, |message| {
+            matches!(
+                message,
+                GoliathMessage::Discovery(Discovery::VehicleLeft { vehicle_id }) if vehicle_id == "Goliath1"
+            )
+        })
+        .await;
+        client
+            .send(GoliathMessage::Discovery(Discovery::ListVehicles))
+            .await
+            .unwrap();
+        let roster = expect_message(&mut client, |message| {
+            matches!(
+                message,
+                GoliathMessage::Discovery(Discovery::VehicleList(_))
+            )
+        })
+        .await;
+        assert_eq!(
+            roster,
+            GoliathMessage::Discovery(Discovery::VehicleList(Vec::new()))
+        );
+        assert_eq!(client.state(), ConnectionState::Registered);
+        drop(vehicle);
+    }
+
+    #[tokio::test]
+    async fn test_node_that_stops_reading_is_disconnected() {
+        let backend = start_backend(&[
+            (NodeType::Vehicle, "Goliath1"),
+            (NodeType::Client, "Operator"),
+        ])
+        .await;
+        let mut client = backend.connect("Operator").await;
+        let mut vehicle = backend.connect_raw("Goliath1").await;
+
+        client
+            .send(GoliathMessage::Session(SessionControl::Request {
+                vehicle_id: "Goliath1".to_string(),
+            }))
+            .await
+            .unwrap();
+        loop {
+            if let GoliathMessage::Session(SessionControl::Started { .. }) =
+                recv_raw(&mut vehicle).await
+            {
+                break;
+            }
+        }
+
+        // The vehicle is never read again, so once the socket buffers are full the relayed
+        // messages pile up in its queue, until one no longer fits
+        let filler = GoliathMessage::error("x".repeat(64 * 1024));
+        let ended = timeout(LIMIT * 4, async {
+            loop {
+                client.send(filler.clone()).await.unwrap();
+                while let Ok(message) = client.try_recv() {
+                    if let GoliathMessage::Session(SessionControl::Ended { reason, .. }) = message {
+                        return reason;
+                    }
+                }
+            }
+        })
+        .await
+        .expect("The vehicle was never disconnected");
+        assert_eq!(ended, "Peer stopped reading");
+        assert_eq!(client.state(), ConnectionState::Registered);
+        drop(vehicle);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_registrations_are_capped_and_time_out() {
+        let backend = start_backend_with(
+            &[],
+            TestPolicies {
+                handshake: HandshakePolicy {
+                    timeout: Duration::from_millis(300),
+                    max_in_flight: 1,
+                },
+                ..Default::default()
+            },
+        )
+        .await;
+
+        // Gets through the handshake, then never answers the challenge
+        let mut stalled = backend.upgrade().await;
+        assert!(matches!(
+            recv_raw(&mut stalled).await,
+            GoliathMessage::Challenge(_)
+        ));
+        // Its registration still takes up the only slot
+        let mut turned_away = TcpStream::connect(backend.addr).await.unwrap();
+        let mut buf = [0; 1];
+        assert!(matches!(
+            timeout(Duration::from_millis(200), turned_away.read(&mut buf)).await,
+            Ok(Ok(0) | Err(_))
+        ));
+
+        let dropped = timeout(Duration::from_secs(1), async {
+            while let Some(Ok(_)) = stalled.next().await {}
+        })
+        .await;
+        assert!(dropped.is_ok(), "Stalled registration was never dropped");
+        // The slot is free again
+        let mut next = backend.upgrade().await;
+        assert!(matches!(
+            recv_raw(&mut next).await,
+            GoliathMessage::Challenge(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_legacy_nodes_register_while_allowed() {
+        for allow_legacy_auth in [true, false] {
+            let backend = start_backend_with(
+                &[(NodeType::Client, "EmilyClient")],
+                TestPolicies {
+                    allow_legacy_auth,
+                    ..Default::default()
+                },
+            )
+            .await;
+            let (_, key) = backend.credentials("EmilyClient");
+            let timestamp = unix_timestamp_millis();
+            let hash = generate_registration_hash("EmilyClient", timestamp, key).unwrap();
+
+            // Byte for byte what a client from before the challenge sends, straight after connecting
+            let mut ws_conn = backend.upgrade().await;
+            ws_conn
+                .send(Message::Text(format!(
+                    r#"{{"id":"EmilyClient","timestamp":{timestamp},"hash":"{hash}","node_type":"Client"}}"#
+                )))
+                .await
+                .unwrap();
+
+            assert!(matches!(
+                recv_raw(&mut ws_conn).await,
+                GoliathMessage::Challenge(_)
+            ));
+            let response = if allow_legacy_auth {
+                RegistrationResponse::accepted()
+            } else {
+                RegistrationResponse::rejected(RegistrationError::InvalidRequest)
+            };
+            assert_eq!(
+                recv_raw(&mut ws_conn).await,
+                GoliathMessage::RegistrationResponse(response)
+            );
         }
     }
 }
@@ -0,0 +1,139 @@
+use crate::server_core::roster::vehicle_roster;
+use crate::server_core::sessions::{SessionError, SessionRegistry};
+use crate::server_core::types::ConnectedNode;
+use goliath_common::{
+    core::NodeType,
+    protocol::{Discovery, GoliathMessage, Safety, SafetyStatus, SessionControl},
+};
+use std::collections::HashMap;
+
+/// Routes a message from `id`. `peers` is the map of the opposite node type, which is where
+/// session traffic is forwarded to
+pub fn handle_message(
+    node_type: NodeType,
+    id: &str,
+    node: &ConnectedNode,
+    message: GoliathMessage,
+    peers: &HashMap<String, ConnectedNode>,
+    sessions: &mut SessionRegistry,
+) {
+    // Still relayed to the client like everything else, but worth a trace on the backend too
+    if let GoliathMessage::Safety(Safety::Status(SafetyStatus::Failsafe { reason })) = &message {
+        log::warn!("{node_type:?} {id} entered failsafe: {reason}");
+    }
+
+    match message {
+        GoliathMessage::Session(SessionControl::Request { vehicle_id })
+            if node_type == NodeType::Client =>
+        {
+            start_session(id, node, &vehicle_id, peers, sessions);
+        }
+        GoliathMessage::Session(SessionControl::End) => {
+            end_session(
+                node_type,
+                id,
+                "Session ended by peer",
+                Some(node),
+                peers,
+                sessions,
+            );
+        }
+        GoliathMessage::Discovery(Discovery::ListVehicles) if node_type == NodeType::Client => {
+            node.send(&GoliathMessage::Discovery(Discovery::VehicleList(
+                vehicle_roster(peers, sessions),
+            )))
+            .ok();
+        }
+        GoliathMessage::Session(_)
+        | GoliathMessage::Discovery(_)
+        | GoliathMessage::Registration(_)
+        | GoliathMessage::RegistrationResponse(_)
+        | GoliathMessage::Shutdown(_) => {
+            node.send(&GoliathMessage::error("Unexpected message")).ok();
+        }
+        message => {
+            let peer = sessions
+                .get_by_node(node_type, id)
+                .and_then(|session| session.peer_of(node_type))
+                .and_then(|(_, peer_id)| peers.get(peer_id));
+
+            match (peer, &message) {
+                (Some(peer), _) => {
+                    peer.send(&message).ok();
+                }
+                (None, GoliathMessage::Drive(_)) => {
+                    node.send(&GoliathMessage::error(SessionError::NotInSession))
+                        .ok();
+                }
+                (None, _) => log::trace!("Dropping {message:?} from {id}, not in a session"),
+            }
+        }
+    }
+}
+
+fn start_session(
+    client_id: &str,
+    client: &ConnectedNode,
+    vehicle_id: &str,
+    vehicles: &HashMap<String, ConnectedNode>,
+    sessions: &mut SessionRegistry,
+) {
+    let Some(vehicle) = vehicles.get(vehicle_id) else {
+        client
+            .send(&GoliathMessage::error(SessionError::VehicleNotFound))
+            .ok();
+        return;
+    };
+
+    match sessions.create(client_id, vehicle_id) {
+        Ok(session) => {
+            log::info!("Started session {}", session.session_id);
+            client
+                .send(&GoliathMessage::Session(SessionControl::Started {
+                    session_id: session.session_id.clone(),
+                    peer_id: vehicle_id.to_string(),
+                }))
+                .ok();
+            vehicle
+                .send(&GoliathMessage::Session(SessionControl::Started {
+                    session_id: session.session_id.clone(),
+                    peer_id: client_id.to_string(),
+                }))
+                .ok();
+        }
+        Err(err) => {
+            client.send(&GoliathMessage::error(err)).ok();
+        }
+    }
+}
+
+/// Tears down the session `id` is part of, and notifies whoever is still connected
+pub fn end_session(
+    node_type: NodeType,
+    id: &str,
+    reason: &str,
+    node: Option<&ConnectedNode>,
+    peers: &HashMap<String, ConnectedNode>,
+    sessions: &mut SessionRegistry,
+) {
+    let Some(session) = sessions.remove_by_node(node_type, id) else {
+        return;
+    };
+
+    log::info!("Session {} ended: {reason}", session.session_id);
+    let ended = GoliathMessage::Session(SessionControl::Ended {
+        session_id: session.session_id.clone(),
+        reason: reason.to_string(),
+    });
+
+    if let Some(node) = node {
+        node.send(&ended).ok();
+    }
+
+    if let Some(peer) = session
+        .peer_of(node_type)
+        .and_then(|(_, peer_id)| peers.get(peer_id))
+    {
+        peer.send(&ended).ok();
+    }
+}
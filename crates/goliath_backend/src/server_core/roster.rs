@@ -0,0 +1,95 @@
+use crate::server_core::sessions::SessionRegistry;
+use crate::server_core::types::ConnectedNode;
+use goliath_common::{
+    core::NodeType,
+    protocol::{Discovery, VehicleInfo},
+};
+use std::collections::HashMap;
+
+pub fn vehicle_roster(
+    vehicles: &HashMap<String, ConnectedNode>,
+    sessions: &SessionRegistry,
+) -> Vec<VehicleInfo> {
+    let mut roster = vehicles
+        .iter()
+        .map(|(id, vehicle)| VehicleInfo {
+            id: id.clone(),
+            online_since: vehicle.connected_at,
+            in_session: sessions.get_by_node(NodeType::Vehicle, id).is_some(),
+        })
+        .collect::<Vec<_>>();
+
+    roster.sort_by(|a, b| a.id.cmp(&b.id));
+    roster
+}
+
+/// Remembers the last roster that was pushed to clients, so only the changes are sent
+#[derive(Default)]
+pub struct RosterTracker {
+    last_roster: HashMap<String, VehicleInfo>,
+}
+
+impl RosterTracker {
+    pub fn update(&mut self, roster: Vec<VehicleInfo>) -> Vec<Discovery> {
+        let mut changes = Vec::new();
+        let mut current_roster = HashMap::with_capacity(roster.len());
+
+        for vehicle in roster {
+            match self.last_roster.remove(&vehicle.id) {
+                None => changes.push(Discovery::VehicleJoined(vehicle.clone())),
+                Some(previous) if previous != vehicle => {
+                    changes.push(Discovery::VehicleUpdated(vehicle.clone()))
+                }
+                Some(_) => {}
+            }
+            current_roster.insert(vehicle.id.clone(), vehicle);
+        }
+
+        // Whatever is left was not in the current roster
+        changes.extend(
+            self.last_roster
+                .drain()
+                .map(|(vehicle_id, _)| Discovery::VehicleLeft { vehicle_id }),
+        );
+
+        self.last_roster = current_roster;
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RosterTracker;
+    use goliath_common::protocol::{Discovery, VehicleInfo};
+
+    #[test]
+    fn test_roster_changes() {
+        let vehicle = VehicleInfo {
+            id: "EmilyVehicle".to_string(),
+            online_since: 0,
+            in_session: false,
+        };
+        let mut tracker = RosterTracker::default();
+
+        assert_eq!(
+            tracker.update(vec![vehicle.clone()]),
+            vec![Discovery::VehicleJoined(vehicle.clone())]
+        );
+        assert!(tracker.update(vec![vehicle.clone()]).is_empty());
+
+        let busy_vehicle = VehicleInfo {
+            in_session: true,
+            ..vehicle
+        };
+        assert_eq!(
+            tracker.update(vec![busy_vehicle.clone()]),
+            vec![Discovery::VehicleUpdated(busy_vehicle)]
+        );
+        assert_eq!(
+            tracker.update(vec![]),
+            vec![Discovery::VehicleLeft {
+                vehicle_id: "EmilyVehicle".to_string()
+            }]
+        );
+    }
+}
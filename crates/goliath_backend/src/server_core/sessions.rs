@@ -0,0 +1,151 @@
+use goliath_common::core::{unix_timestamp_millis, NodeType};
+use std::collections::HashMap;
+use thiserror::Error;
+
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum SessionError {
+    #[error("Client is already in a session")]
+    ClientBusy,
+    #[error("Vehicle is already controlled by another client")]
+    VehicleBusy,
+    #[error("No such vehicle is connected")]
+    VehicleNotFound,
+    #[error("Node is not in a session")]
+    NotInSession,
+    #[error("The maximum number of concurrent sessions has been reached")]
+    LimitReached,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Session {
+    pub(crate) session_id: String,
+    pub(crate) client_id: String,
+    pub(crate) vehicle_id: String,
+}
+
+impl Session {
+    /// Returns the type and id of the node on the other end of the session
+    pub fn peer_of(&self, node_type: NodeType) -> Option<(NodeType, &str)> {
+        match node_type {
+            NodeType::Client => Some((NodeType::Vehicle, &self.vehicle_id)),
+            NodeType::Vehicle => Some((NodeType::Client, &self.client_id)),
+            NodeType::Unsorted => None,
+        }
+    }
+}
+
+/// Keeps track of which client controls which vehicle, a node can only ever be in one session
+#[derive(Default)]
+pub struct SessionRegistry {
+    sessions: HashMap<String, Session>,
+    client_sessions: HashMap<String, String>,
+    vehicle_sessions: HashMap<String, String>,
+    max_sessions: Option<usize>,
+}
+
+impl SessionRegistry {
+    pub fn with_limit(max_sessions: Option<usize>) -> Self {
+        Self {
+            max_sessions,
+            ..Default::default()
+        }
+    }
+
+    pub fn create(&mut self, client_id: &str, vehicle_id: &str) -> Result<&Session, SessionError> {
+        if self.client_sessions.contains_key(client_id) {
+            return Err(SessionError::ClientBusy);
+        }
+        if self.vehicle_sessions.contains_key(vehicle_id) {
+            return Err(SessionError::VehicleBusy);
+        }
+        if self
+            .max_sessions
+            .is_some_and(|max_sessions| self.sessions.len() >= max_sessions)
+        {
+            return Err(SessionError::LimitReached);
+        }
+
+        let session_id = format!("{client_id}-{vehicle_id}-{}", unix_timestamp_millis());
+        self.client_sessions
+            .insert(client_id.to_string(), session_id.clone());
+        self.vehicle_sessions
+            .insert(vehicle_id.to_string(), session_id.clone());
+
+        Ok(self
+            .sessions
+            .entry(session_id.clone())
+            .or_insert_with(|| Session {
+                session_id,
+                client_id: client_id.to_string(),
+                vehicle_id: vehicle_id.to_string(),
+            }))
+    }
+
+    pub fn get_by_node(&self, node_type: NodeType, id: &str) -> Option<&Session> {
+        let session_id = match node_type {
+            NodeType::Client => self.client_sessions.get(id),
+            NodeType::Vehicle => self.vehicle_sessions.get(id),
+            NodeType::Unsorted => None,
+        }?;
+
+        self.sessions.get(session_id)
+    }
+
+    pub fn remove_by_node(&mut self, node_type: NodeType, id: &str) -> Option<Session> {
+        let session_id = match node_type {
+            NodeType::Client => self.client_sessions.get(id),
+            NodeType::Vehicle => self.vehicle_sessions.get(id),
+            NodeType::Unsorted => None,
+        }?
+        .clone();
+
+        let session = self.sessions.remove(&session_id)?;
+        self.client_sessions.remove(&session.client_id);
+        self.vehicle_sessions.remove(&session.vehicle_id);
+        Some(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SessionError, SessionRegistry};
+    use goliath_common::core::NodeType;
+
+    #[test]
+    fn test_session_lifecycle() {
+        let mut sessions = SessionRegistry::default();
+        let session = sessions
+            .create("EmilyClient", "EmilyVehicle")
+            .unwrap()
+            .clone();
+
+        assert_eq!(
+            sessions.create("OtherClient", "EmilyVehicle"),
+            Err(SessionError::VehicleBusy)
+        );
+        assert_eq!(
+            sessions.create("EmilyClient", "OtherVehicle"),
+            Err(SessionError::ClientBusy)
+        );
+        assert_eq!(
+            session.peer_of(NodeType::Vehicle),
+            Some((NodeType::Client, "EmilyClient"))
+        );
+
+        assert_eq!(
+            sessions.remove_by_node(NodeType::Vehicle, "EmilyVehicle"),
+            Some(session)
+        );
+        assert!(sessions
+            .get_by_node(NodeType::Client, "EmilyClient")
+            .is_none());
+        assert!(sessions.create("OtherClient", "EmilyVehicle").is_ok());
+
+        let mut sessions = SessionRegistry::with_limit(Some(1));
+        assert!(sessions.create("EmilyClient", "EmilyVehicle").is_ok());
+        assert_eq!(
+            sessions.create("OtherClient", "OtherVehicle"),
+            Err(SessionError::LimitReached)
+        );
+    }
+}
@@ -1,15 +1,149 @@
+use crate::credential_store::CredentialStore;
+use crate::security::ReplayGuard;
 use goliath_common::core::NodeType;
+use goliath_common::protocol::{GoliathMessage, ProtocolError, WireEncoding};
+use goliath_common::websocket::{MessageClass, MessageSender};
 use goliath_common::ClientConnection;
-use std::collections::HashMap;
+use serde::Deserialize;
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync as TokioSync;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
 
-pub struct RegistrationTypeResponse {
+#[derive(Copy, Clone, Debug)]
+pub struct RegistrationPolicy {
+    /// How far a registration timestamp may drift from the server clock
+    pub window: Duration,
+    /// Whether nodes that predate the challenge-response handshake may still register
+    pub allow_legacy_auth: bool,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SessionPolicy {
+    /// How many sessions may run at once, unlimited when `None`
+    pub max_sessions: Option<usize>,
+}
+
+/// What happens when a node registers with an id that is already connected
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicateIdPolicy {
+    /// The newcomer is turned away, the connected node carries on
+    Reject,
+    /// The connected node is let go of and told why, usually it is a connection that hasn't
+    /// noticed yet that its node came back
+    #[default]
+    Replace,
+    /// Both stay, the newcomer is registered as `id#2`, `id#3` and so on
+    AllowMultiple,
+}
+
+impl FromStr for DuplicateIdPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "reject" => Ok(Self::Reject),
+            "replace" => Ok(Self::Replace),
+            "allow-multiple" => Ok(Self::AllowMultiple),
+            _ => Err(format!(
+                "Unknown duplicate id policy {value}, expected reject, replace or allow-multiple"
+            )),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct IdentityPolicy {
+    pub clients: DuplicateIdPolicy,
+    pub vehicles: DuplicateIdPolicy,
+}
+
+impl IdentityPolicy {
+    pub fn duplicates(&self, node_type: NodeType) -> DuplicateIdPolicy {
+        match node_type {
+            NodeType::Vehicle => self.vehicles,
+            _ => self.clients,
+        }
+    }
+}
+
+/// Everything a registration task needs in order to authenticate a node
+#[derive(Clone)]
+pub struct RegistrationContext {
+    pub(crate) policy: RegistrationPolicy,
+    pub(crate) replay_guard: Arc<TokioSync::Mutex<ReplayGuard>>,
+    pub(crate) credential_store: Arc<TokioSync::Mutex<CredentialStore>>,
+}
+
+/// A node that passed registration, on its way to the server core
+pub struct RegisteredNode {
     pub(crate) id: String,
     pub(crate) node_type: NodeType,
     pub(crate) ws_conn: ClientConnection,
+    pub(crate) encoding: WireEncoding,
+}
+
+/// Tells connections apart that registered under the same id, so events from a connection that
+/// has since been replaced are never mistaken for the current one
+#[derive(Clone, Debug, PartialEq)]
+pub struct NodeKey {
+    pub(crate) node_type: NodeType,
+    pub(crate) id: String,
+    pub(crate) connection_id: u64,
 }
 
-pub type IdentifiedNode = (String, ClientConnection);
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DisconnectReason {
+    Closed,
+    TimedOut,
+    /// Its control messages piled up, so it must have stopped reading
+    Stalled,
+}
+
+/// Reported to the server core by the reader task of every registered node
+pub enum NodeEvent {
+    Received {
+        node: NodeKey,
+        message: Result<GoliathMessage, ProtocolError>,
+    },
+    Disconnected {
+        node: NodeKey,
+        reason: DisconnectReason,
+    },
+}
 
-pub type IdentifiedNodeList = Arc<TokioSync::Mutex<HashMap<String, ClientConnection>>>;
+/// A registered node, as seen by the server core. The socket itself belongs to the node's reader
+/// and writer tasks, dropping this lets the writer flush what is queued and close the connection
+pub struct ConnectedNode {
+    pub(crate) connection_id: u64,
+    pub(crate) outgoing_tx: MessageSender,
+    pub(crate) connected_at: u128,
+    pub(crate) writer: JoinHandle<()>,
+    // Makes the reader report the node as disconnected, and the writer give up on it
+    pub(crate) stalled: TokioSync::watch::Sender<bool>,
+}
+
+impl ConnectedNode {
+    /// Queues the message for the node's writer task, drive commands and telemetry never wait
+    /// behind an older one, and nothing here waits on the node itself. A control message that
+    /// doesn't fit gets the node disconnected, rather than leaving its peer with a gap
+    pub fn send(&self, message: &GoliathMessage) -> Result<(), ()> {
+        match self.outgoing_tx.try_send(message.clone()) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(message)) => {
+                self.outgoing_tx.count_dropped(MessageClass::of(&message));
+                if !self.stalled.send_replace(true) {
+                    log::warn!("Could not queue message, disconnecting the node");
+                }
+                Err(())
+            }
+            Err(err) => {
+                log::warn!("Could not queue message: {err}");
+                Err(())
+            }
+        }
+    }
+}
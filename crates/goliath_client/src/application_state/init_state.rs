@@ -1,62 +1,84 @@
-use super::{ApplicationState, ApplicationStateTrait, NewlyConnected, PendingState};
+use super::{
+    ApplicationState, ApplicationStateTrait, RegisteredState, RejectedState, SettingsState,
+};
 use crate::config::ApplicationConfig;
-use eframe::egui::{Align2, Color32, FontFamily, FontId, Rect, Ui};
-use goliath_common::websocket::{goliath_ws_connect, ConnectResult};
-use std::time::Duration;
-use tokio::{runtime::Runtime, task::JoinHandle};
+use eframe::egui::{Color32, FontFamily, FontId, Rect, RichText, Ui};
+use goliath_common::websocket::{ConnectionState, ReconnectingConnection};
+use tokio::runtime::Runtime;
 
+/// Waits for the connection to register, it keeps retrying on its own in the meantime
 #[derive(Debug)]
 pub struct InitState {
-    connection_request: Option<JoinHandle<ConnectResult>>,
+    connection: Option<ReconnectingConnection>,
+    connection_state: ConnectionState,
+    settings_requested: bool,
 }
 
 impl InitState {
     pub fn new() -> Self {
         Self {
-            connection_request: None,
+            connection: None,
+            connection_state: ConnectionState::Connecting { attempt: 0 },
+            settings_requested: false,
+        }
+    }
+
+    /// Picks up a connection that was lost after registering, and is now re-registering
+    pub fn with_connection(connection: ReconnectingConnection) -> Self {
+        Self {
+            connection_state: connection.state(),
+            connection: Some(connection),
+            settings_requested: false,
         }
     }
 }
 
 impl ApplicationStateTrait for InitState {
-    fn update(&mut self, rt: &Runtime, config: &ApplicationConfig) -> Option<ApplicationState> {
-        if let Some(join_handle) = self.connection_request.take() {
-            if join_handle.is_finished() {
-                let res = rt
-                    .block_on(join_handle)
-                    .map_err(|err| log::error!("Could not join connection task: {err}"))
-                    .and_then(|join_handle_result| join_handle_result);
-
-                return Some(if let Ok(conn) = res {
-                    ApplicationState::NewlyConnected(NewlyConnected::new(conn))
-                } else {
-                    ApplicationState::Pending(PendingState::new(
-                        ApplicationState::Init(InitState::new()),
-                        "Unable to connect, retrying".to_string(),
-                        rt,
-                        Duration::from_secs(2),
-                    ))
-                });
-            } else {
-                self.connection_request = Some(join_handle); // Wait some more
-            }
-        } else {
-            self.connection_request = Some(rt.spawn({
-                let ws_address = config.ws_address.clone();
-                async move { goliath_ws_connect(format!("wss://{ws_address}")).await }
-            }))
+    fn update(&mut self, rt: &Runtime, config: &mut ApplicationConfig) -> Option<ApplicationState> {
+        // Without a way to verify the backend there is nothing to connect to yet
+        if self.settings_requested || config.server_verification.is_none() {
+            return Some(ApplicationState::Settings(SettingsState::new(config)));
+        }
+
+        if self.connection.is_none() {
+            let _guard = rt.enter();
+            self.connection = config
+                .connection_options()
+                .map(ReconnectingConnection::spawn);
         }
+        self.connection_state = self.connection.as_ref()?.state();
 
-        None
+        match &self.connection_state {
+            ConnectionState::Registered => Some(ApplicationState::Registered(
+                RegisteredState::new(self.connection.take()?),
+            )),
+            ConnectionState::Rejected(err) => {
+                Some(ApplicationState::Rejected(RejectedState::new(err.clone())))
+            }
+            _ => None,
+        }
     }
 
-    fn draw(&self, ui: &mut Ui, current_rect: Rect) {
-        ui.painter().text(
-            current_rect.min,
-            Align2::LEFT_TOP,
-            "Connecting to server...",
-            FontId::new(24.0, FontFamily::Name("main".into())),
-            Color32::from_rgb(200, 22, 5),
+    fn draw(&mut self, ui: &mut Ui, _current_rect: Rect) {
+        let status = match &self.connection_state {
+            ConnectionState::Connecting { attempt: 0 } => "Connecting to server...".to_string(),
+            ConnectionState::Connecting { attempt } => {
+                format!("Connecting to server, attempt {}...", attempt + 1)
+            }
+            ConnectionState::Connected => "Registering client...".to_string(),
+            ConnectionState::Lost { reason, retry_in } => {
+                format!("{reason}, retrying in {:.1}s", retry_in.as_secs_f32())
+            }
+            ConnectionState::Registered | ConnectionState::Rejected(_) => String::new(),
+        };
+        ui.label(
+            RichText::new(status)
+                .font(FontId::new(24.0, FontFamily::Name("main".into())))
+                .color(Color32::from_rgb(200, 22, 5)),
         );
+
+        if ui.button("Settings").clicked() {
+            self.settings_requested = true;
+        }
     }
 }
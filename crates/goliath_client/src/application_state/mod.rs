@@ -1,19 +1,21 @@
 pub use init_state::InitState;
-pub use newly_connected::NewlyConnected;
-pub use pending_state::PendingState;
+pub use registered_state::RegisteredState;
+pub use rejected_state::RejectedState;
+pub use settings_state::SettingsState;
 
 use crate::config::ApplicationConfig;
 use eframe::egui::{Rect, Ui};
 use tokio::runtime::Runtime;
 
 mod init_state;
-mod newly_connected;
-mod pending_state;
+mod registered_state;
+mod rejected_state;
+mod settings_state;
 
 pub trait ApplicationStateTrait {
-    fn update(&mut self, rt: &Runtime, config: &ApplicationConfig) -> Option<ApplicationState>;
+    fn update(&mut self, rt: &Runtime, config: &mut ApplicationConfig) -> Option<ApplicationState>;
 
-    fn draw(&self, ui: &mut Ui, current_rect: Rect);
+    fn draw(&mut self, ui: &mut Ui, current_rect: Rect);
 }
 
 #[derive(Debug, Default)]
@@ -21,28 +23,29 @@ pub enum ApplicationState {
     #[default]
     Dummy,
     Init(InitState),
-    NewlyConnected(NewlyConnected),
-    Pending(PendingState),
+    Registered(RegisteredState),
+    Rejected(RejectedState),
+    Settings(SettingsState),
 }
 
 impl ApplicationStateTrait for ApplicationState {
-    fn update(&mut self, rt: &Runtime, config: &ApplicationConfig) -> Option<ApplicationState> {
+    fn update(&mut self, rt: &Runtime, config: &mut ApplicationConfig) -> Option<ApplicationState> {
         match self {
             ApplicationState::Dummy => None,
-            ApplicationState::Pending(pending_state) => pending_state.update(rt, config),
             ApplicationState::Init(init) => init.update(rt, config),
-            ApplicationState::NewlyConnected(newly_connected) => newly_connected.update(rt, config),
+            ApplicationState::Registered(registered) => registered.update(rt, config),
+            ApplicationState::Rejected(rejected) => rejected.update(rt, config),
+            ApplicationState::Settings(settings) => settings.update(rt, config),
         }
     }
 
-    fn draw(&self, ui: &mut Ui, current_rect: Rect) {
+    fn draw(&mut self, ui: &mut Ui, current_rect: Rect) {
         match self {
             ApplicationState::Dummy => {}
-            ApplicationState::Pending(pending_state) => pending_state.draw(ui, current_rect),
             ApplicationState::Init(init) => init.draw(ui, current_rect),
-            ApplicationState::NewlyConnected(newly_connected) => {
-                newly_connected.draw(ui, current_rect)
-            }
+            ApplicationState::Registered(registered) => registered.draw(ui, current_rect),
+            ApplicationState::Rejected(rejected) => rejected.draw(ui, current_rect),
+            ApplicationState::Settings(settings) => settings.draw(ui, current_rect),
         }
     }
 }
@@ -0,0 +1,222 @@
+use super::{ApplicationState, ApplicationStateTrait, InitState};
+use crate::config::ApplicationConfig;
+use eframe::egui::{Color32, FontFamily, FontId, Rect, RichText, Ui};
+use goliath_common::{
+    core::unix_timestamp_millis,
+    protocol::{
+        Discovery, GoliathMessage, Heartbeat, Safety, SafetyStatus, SessionControl, VehicleInfo,
+    },
+    websocket::{ConnectionState, ReconnectingConnection},
+};
+use std::time::{Duration, Instant};
+use tokio::{runtime::Runtime, sync::mpsc::error::TrySendError};
+
+// Well within the vehicle's failsafe deadline, so an idle operator doesn't trip it
+const SESSION_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Debug)]
+pub struct RegisteredState {
+    // Only taken once the connection is lost, and handed back to `InitState` while it re-registers
+    connection: Option<ReconnectingConnection>,
+    vehicles: Vec<VehicleInfo>,
+    active_session: Option<(String, String)>,
+    // As last reported by the vehicle in the active session
+    vehicle_status: Option<SafetyStatus>,
+    last_heartbeat: Instant,
+    notice: Option<String>,
+    roster_requested: bool,
+    // These are set while drawing, and acted upon in the next update
+    requested_vehicle: Option<String>,
+    end_session_requested: bool,
+    rearm_requested: bool,
+}
+
+impl RegisteredState {
+    pub fn new(connection: ReconnectingConnection) -> Self {
+        Self {
+            connection: Some(connection),
+            vehicles: Vec::new(),
+            active_session: None,
+            vehicle_status: None,
+            last_heartbeat: Instant::now(),
+            notice: None,
+            roster_requested: false,
+            requested_vehicle: None,
+            end_session_requested: false,
+            rearm_requested: false,
+        }
+    }
+
+    fn on_message(&mut self, message: GoliathMessage) {
+        match message {
+            GoliathMessage::Discovery(Discovery::VehicleList(vehicles)) => {
+                self.vehicles = vehicles;
+            }
+            GoliathMessage::Discovery(
+                Discovery::VehicleJoined(vehicle) | Discovery::VehicleUpdated(vehicle),
+            ) => match self.vehicles.iter_mut().find(|v| v.id == vehicle.id) {
+                Some(existing) => *existing = vehicle,
+                None => self.vehicles.push(vehicle),
+            },
+            GoliathMessage::Discovery(Discovery::VehicleLeft { vehicle_id }) => {
+                self.vehicles.retain(|vehicle| vehicle.id != vehicle_id);
+            }
+            GoliathMessage::Session(SessionControl::Started {
+                session_id,
+                peer_id,
+            }) => {
+                self.notice = None;
+                self.active_session = Some((session_id, peer_id));
+            }
+            GoliathMessage::Session(SessionControl::Ended { reason, .. }) => {
+                self.notice = Some(reason);
+                self.active_session = None;
+                self.vehicle_status = None;
+            }
+            GoliathMessage::Safety(Safety::Status(status)) if self.active_session.is_some() => {
+                self.vehicle_status = Some(status);
+            }
+            GoliathMessage::Shutdown(notice) => {
+                log::warn!("Backend is going away: {}", notice.reason);
+                self.notice = Some(notice.reason);
+                self.active_session = None;
+                self.vehicle_status = None;
+            }
+            GoliathMessage::Error(err) => {
+                self.notice = Some(err.msg);
+            }
+            message => log::trace!("Ignoring {message:?}"),
+        }
+    }
+
+    fn send(connection: &ReconnectingConnection, message: GoliathMessage) {
+        if let Err(TrySendError::Full(message)) = connection.try_send(message) {
+            log::warn!("Outgoing queue is full, dropping {message:?}");
+        }
+    }
+}
+
+impl ApplicationStateTrait for RegisteredState {
+    fn update(
+        &mut self,
+        _rt: &Runtime,
+        _config: &mut ApplicationConfig,
+    ) -> Option<ApplicationState> {
+        let mut outgoing = Vec::new();
+        if !self.roster_requested {
+            self.roster_requested = true;
+            outgoing.push(GoliathMessage::Discovery(Discovery::ListVehicles));
+        }
+        if let Some(vehicle_id) = self.requested_vehicle.take() {
+            outgoing.push(GoliathMessage::Session(SessionControl::Request {
+                vehicle_id,
+            }));
+        }
+        if std::mem::take(&mut self.end_session_requested) {
+            outgoing.push(GoliathMessage::Session(SessionControl::End));
+        }
+        if std::mem::take(&mut self.rearm_requested) {
+            outgoing.push(GoliathMessage::Safety(Safety::Rearm));
+        }
+        if self.active_session.is_some()
+            && self.last_heartbeat.elapsed() >= SESSION_HEARTBEAT_INTERVAL
+        {
+            self.last_heartbeat = Instant::now();
+            outgoing.push(GoliathMessage::Heartbeat(Heartbeat {
+                timestamp: unix_timestamp_millis(),
+            }));
+        }
+
+        for message in outgoing {
+            Self::send(self.connection.as_ref()?, message);
+        }
+        while let Ok(message) = self.connection.as_mut()?.try_recv() {
+            self.on_message(message);
+        }
+
+        if self.connection.as_ref()?.state() == ConnectionState::Registered {
+            return None;
+        }
+        // Sessions don't survive a lost connection, whatever we knew is stale once we are back
+        Some(ApplicationState::Init(InitState::with_connection(
+            self.connection.take()?,
+        )))
+    }
+
+    fn draw(&mut self, ui: &mut Ui, _current_rect: Rect) {
+        let font = FontId::new(24.0, FontFamily::Name("main".into()));
+
+        match &self.active_session {
+            Some((_, vehicle_id)) => {
+                ui.label(
+                    RichText::new(format!("Controlling {vehicle_id}"))
+                        .font(font)
+                        .color(Color32::from_rgb(5, 200, 22)),
+                );
+                match &self.vehicle_status {
+                    Some(SafetyStatus::Armed) => {
+                        ui.label("Armed");
+                    }
+                    Some(SafetyStatus::Failsafe { reason }) => {
+                        ui.colored_label(
+                            Color32::from_rgb(200, 22, 5),
+                            format!("Failsafe: {reason}"),
+                        );
+                        if ui.button("Arm").clicked() {
+                            self.rearm_requested = true;
+                        }
+                    }
+                    None => {}
+                }
+                if ui.button("End session").clicked() {
+                    self.end_session_requested = true;
+                }
+            }
+            None => {
+                ui.label(
+                    RichText::new("Available vehicles")
+                        .font(font)
+                        .color(Color32::from_rgb(200, 192, 5)),
+                );
+
+                if self.vehicles.is_empty() {
+                    ui.label("No vehicles are online");
+                }
+                for vehicle in self.vehicles.iter() {
+                    ui.horizontal(|ui| {
+                        ui.label(&vehicle.id);
+                        if vehicle.in_session {
+                            ui.label("(in use)");
+                        } else if ui.button("Take control").clicked() {
+                            self.requested_vehicle = Some(vehicle.id.clone());
+                        }
+                    });
+                }
+            }
+        }
+
+        if let Some(connection) = &self.connection {
+            // The backend may have given us another id, when ours was already taken
+            ui.small(format!("Registered as {}", connection.registered_id()));
+        }
+
+        // Only shown once something was actually lost, a healthy link keeps the screen clean
+        if let Some(connection) = &self.connection {
+            let incoming = connection.dropped_incoming();
+            let outgoing = connection.dropped_outgoing();
+            if incoming.total() + outgoing.total() > 0 {
+                ui.small(format!(
+                    "Dropped frames: {} incoming ({} drive, {} telemetry), {} outgoing",
+                    incoming.total(),
+                    incoming.drive,
+                    incoming.telemetry,
+                    outgoing.total(),
+                ));
+            }
+        }
+
+        if let Some(err) = &self.notice {
+            ui.colored_label(Color32::from_rgb(200, 22, 5), err);
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use super::{ApplicationState, ApplicationStateTrait, InitState, SettingsState};
+use crate::config::ApplicationConfig;
+use eframe::egui::{Color32, FontFamily, FontId, Rect, RichText, Ui};
+use goliath_common::security::RegistrationError;
+use tokio::runtime::Runtime;
+
+#[derive(Debug)]
+pub struct RejectedState {
+    reason: RegistrationError,
+    retry_requested: bool,
+    settings_requested: bool,
+}
+
+impl RejectedState {
+    pub fn new(reason: RegistrationError) -> Self {
+        Self {
+            reason,
+            retry_requested: false,
+            settings_requested: false,
+        }
+    }
+}
+
+impl ApplicationStateTrait for RejectedState {
+    fn update(
+        &mut self,
+        _rt: &Runtime,
+        config: &mut ApplicationConfig,
+    ) -> Option<ApplicationState> {
+        if self.settings_requested {
+            return Some(ApplicationState::Settings(SettingsState::new(config)));
+        }
+
+        // Retrying with the same credentials will fail the same way, so only do it when asked to
+        self.retry_requested
+            .then(|| ApplicationState::Init(InitState::new()))
+    }
+
+    fn draw(&mut self, ui: &mut Ui, _current_rect: Rect) {
+        ui.label(
+            RichText::new(format!("Registration rejected: {}", self.reason))
+                .font(FontId::new(24.0, FontFamily::Name("main".into())))
+                .color(Color32::from_rgb(200, 22, 5)),
+        );
+
+        if ui.button("Retry").clicked() {
+            self.retry_requested = true;
+        }
+        if ui.button("Settings").clicked() {
+            self.settings_requested = true;
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use super::{ApplicationState, ApplicationStateTrait, InitState};
+use crate::config::ApplicationConfig;
+use eframe::egui::{Color32, ComboBox, FontFamily, FontId, Grid, Rect, RichText, TextEdit, Ui};
+use goliath_common::{
+    protocol::WireEncoding,
+    security::{derive_node_key, ClientCertificate, ServerVerification},
+};
+use tokio::runtime::Runtime;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum VerificationKind {
+    Fingerprint,
+    PinnedCertificate,
+    PrivateCa,
+    // Only offered where it would be honoured, so release builds can't save a config they refuse
+    #[cfg(feature = "development")]
+    Insecure,
+}
+
+impl VerificationKind {
+    const ALL: &'static [Self] = &[
+        Self::Fingerprint,
+        Self::PinnedCertificate,
+        Self::PrivateCa,
+        #[cfg(feature = "development")]
+        Self::Insecure,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Fingerprint => "Pinned fingerprint",
+            Self::PinnedCertificate => "Pinned certificate file",
+            Self::PrivateCa => "Private CA file",
+            #[cfg(feature = "development")]
+            Self::Insecure => "Don't verify (development only)",
+        }
+    }
+
+    fn needs_value(&self) -> bool {
+        #[cfg(feature = "development")]
+        if *self == Self::Insecure {
+            return false;
+        }
+        true
+    }
+}
+
+// Everything is edited as text, and only turned back into a config once saved
+#[derive(Debug)]
+struct SettingsDraft {
+    ws_address: String,
+    client_id: String,
+    key: String,
+    wire_encoding: WireEncoding,
+    verification_kind: VerificationKind,
+    verification_value: String,
+    client_cert: String,
+    client_cert_key: String,
+}
+
+impl SettingsDraft {
+    fn from_config(config: &ApplicationConfig) -> Self {
+        let (verification_kind, verification_value) = match &config.server_verification {
+            None => (VerificationKind::Fingerprint, String::new()),
+            Some(ServerVerification::Fingerprint(fingerprint)) => {
+                (VerificationKind::Fingerprint, fingerprint.clone())
+            }
+            Some(ServerVerification::PinnedCertificate(path)) => (
+                VerificationKind::PinnedCertificate,
+                path.display().to_string(),
+            ),
+            Some(ServerVerification::PrivateCa(path)) => {
+                (VerificationKind::PrivateCa, path.display().to_string())
+            }
+            #[cfg(feature = "development")]
+            Some(ServerVerification::Insecure) => (VerificationKind::Insecure, String::new()),
+            // Left over from a development build, this one has to pick something it will honour
+            #[cfg(not(feature = "development"))]
+            Some(ServerVerification::Insecure) => (VerificationKind::Fingerprint, String::new()),
+        };
+        let (client_cert, client_cert_key) = match &config.client_certificate {
+            Some(client_certificate) => (
+                client_certificate.cert.display().to_string(),
+                client_certificate.key.display().to_string(),
+            ),
+            None => (String::new(), String::new()),
+        };
+
+        Self {
+            ws_address: config.ws_address.clone(),
+            client_id: config.client_id.clone(),
+            key: config.key.clone(),
+            wire_encoding: config.wire_encoding,
+            verification_kind,
+            verification_value,
+            client_cert,
+            client_cert_key,
+        }
+    }
+
+    /// Builds the config this draft describes, or explains what is wrong with it
+    fn to_config(&self, current: &ApplicationConfig) -> Result<ApplicationConfig, String> {
+        let ws_address = self.ws_address.trim();
+        let client_id = self.client_id.trim();
+        let verification_value = self.verification_value.trim();
+        if ws_address.is_empty() {
+            return Err("The server address is required".to_string());
+        }
+        if client_id.is_empty() {
+            return Err("The client id is required".to_string());
+        }
+
+        let server_verification = match self.verification_kind {
+            #[cfg(feature = "development")]
+            VerificationKind::Insecure => ServerVerification::Insecure,
+            _ if verification_value.is_empty() => {
+                return Err(format!("{} is required", self.verification_kind.label()))
+            }
+            VerificationKind::Fingerprint => {
+                ServerVerification::Fingerprint(verification_value.to_string())
+            }
+            VerificationKind::PinnedCertificate => {
+                ServerVerification::PinnedCertificate(verification_value.into())
+            }
+            VerificationKind::PrivateCa => ServerVerification::PrivateCa(verification_value.into()),
+        };
+
+        let client_certificate = match (self.client_cert.trim(), self.client_cert_key.trim()) {
+            ("", "") => None,
+            ("", _) | (_, "") => {
+                return Err("A client certificate needs both a certificate and a key".to_string())
+            }
+            (cert, key) => Some(ClientCertificate {
+                cert: cert.into(),
+                key: key.into(),
+            }),
+        };
+        if client_certificate.is_none() && derive_node_key(client_id, &self.key).is_err() {
+            return Err("The secret key is not valid base64".to_string());
+        }
+
+        Ok(ApplicationConfig {
+            ws_address: ws_address.to_string(),
+            client_id: client_id.to_string(),
+            key: self.key.clone(),
+            wire_encoding: self.wire_encoding,
+            server_verification: Some(server_verification),
+            client_certificate,
+            path: current.path.clone(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct SettingsState {
+    draft: SettingsDraft,
+    error: Option<String>,
+    // These are set while drawing, and acted upon in the next update
+    save_requested: bool,
+    cancel_requested: bool,
+}
+
+impl SettingsState {
+    pub fn new(config: &ApplicationConfig) -> Self {
+        Self {
+            draft: SettingsDraft::from_config(config),
+            error: None,
+            save_requested: false,
+            cancel_requested: false,
+        }
+    }
+}
+
+impl ApplicationStateTrait for SettingsState {
+    fn update(
+        &mut self,
+        _rt: &Runtime,
+        config: &mut ApplicationConfig,
+    ) -> Option<ApplicationState> {
+        if self.cancel_requested {
+            return Some(ApplicationState::Init(InitState::new()));
+        }
+        if !std::mem::take(&mut self.save_requested) {
+            return None;
+        }
+
+        let new_config = match self.draft.to_config(config) {
+            Ok(new_config) => new_config,
+            Err(err) => {
+                self.error = Some(err);
+                return None;
+            }
+        };
+        if let Err(err) = new_config.save() {
+            log::error!("{err}");
+            self.error = Some(err.to_string());
+            return None;
+        }
+
+        log::info!("Saved settings to {}", new_config.path.display());
+        *config = new_config;
+        Some(ApplicationState::Init(InitState::new()))
+    }
+
+    fn draw(&mut self, ui: &mut Ui, _current_rect: Rect) {
+        ui.label(
+            RichText::new("Settings")
+                .font(FontId::new(24.0, FontFamily::Name("main".into())))
+                .color(Color32::from_rgb(200, 192, 5)),
+        );
+
+        let draft = &mut self.draft;
+        Grid::new("settings_grid")
+            .num_columns(2)
+            .spacing([16.0, 8.0])
+            .show(ui, |ui| {
+                ui.label("Server address");
+                ui.text_edit_singleline(&mut draft.ws_address);
+                ui.end_row();
+
+                ui.label("Client id");
+                ui.text_edit_singleline(&mut draft.client_id);
+                ui.end_row();
+
+                ui.label("Secret key");
+                ui.add(TextEdit::singleline(&mut draft.key).password(true));
+                ui.end_row();
+
+                ui.label("Wire encoding");
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut draft.wire_encoding, WireEncoding::Bincode, "Bincode");
+                    ui.radio_value(&mut draft.wire_encoding, WireEncoding::Json, "JSON");
+                });
+                ui.end_row();
+
+                ui.label("Server verification");
+                ComboBox::from_id_source("verification_kind")
+                    .selected_text(draft.verification_kind.label())
+                    .show_ui(ui, |ui| {
+                        for &kind in VerificationKind::ALL {
+                            ui.selectable_value(&mut draft.verification_kind, kind, kind.label());
+                        }
+                    });
+                ui.end_row();
+
+                if draft.verification_kind.needs_value() {
+                    ui.label(draft.verification_kind.label());
+                    ui.text_edit_singleline(&mut draft.verification_value);
+                    ui.end_row();
+                }
+
+                ui.label("Client certificate (optional)");
+                ui.text_edit_singleline(&mut draft.client_cert);
+                ui.end_row();
+
+                ui.label("Client certificate key");
+                ui.text_edit_singleline(&mut draft.client_cert_key);
+                ui.end_row();
+            });
+
+        if let Some(error) = &self.error {
+            ui.colored_label(Color32::from_rgb(200, 22, 5), error);
+        }
+
+        ui.horizontal(|ui| {
+            if ui.button("Save and connect").clicked() {
+                self.save_requested = true;
+            }
+            if ui.button("Cancel").clicked() {
+                self.cancel_requested = true;
+            }
+        });
+    }
+}
@@ -1,6 +1,198 @@
-#[derive(Default)]
+use clap::Args;
+use goliath_common::{
+    core::NodeType,
+    protocol::WireEncoding,
+    security::{ClientCertificate, ServerVerification, TlsOverrides},
+    websocket::{Backoff, ConnectionOptions, HeartbeatPolicy, NodeCredentials},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    env, fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+
+const CONFIG_FILE: &str = "client.toml";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Could not access config file {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("Config file {0} is malformed: {1}")]
+    Malformed(PathBuf, toml::de::Error),
+    #[error("Could not serialize the config: {0}")]
+    Serialize(#[from] toml::ser::Error),
+    #[error("No config directory found, pass --config explicitly")]
+    NoConfigDir,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
 pub struct ApplicationConfig {
     pub ws_address: String,
     pub client_id: String,
     pub key: String,
+    pub wire_encoding: WireEncoding,
+    /// The client refuses to connect until this is set, see the settings screen
+    pub server_verification: Option<ServerVerification>,
+    /// When set, the client registers through its certificate rather than `key`
+    pub client_certificate: Option<ClientCertificate>,
+    // Where the config was loaded from, and where the settings screen saves it to
+    #[serde(skip)]
+    pub path: PathBuf,
+}
+
+impl Default for ApplicationConfig {
+    fn default() -> Self {
+        Self {
+            ws_address: "localhost:8555".to_string(),
+            client_id: String::new(),
+            key: String::new(),
+            wire_encoding: WireEncoding::Bincode,
+            server_verification: None,
+            client_certificate: None,
+            path: PathBuf::new(),
+        }
+    }
+}
+
+/// Command line flags, which win over what the settings screen saved until they're dropped again
+#[derive(Debug, Default, Args)]
+pub struct ConfigOverrides {
+    /// Path to the TOML config file, defaults to goliath/client.toml in the user's config directory
+    #[arg(long, env = "GOLIATH_CLIENT_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Backend address, as host:port
+    #[arg(long, env = "GOLIATH_SERVER_ADDRESS")]
+    pub server: Option<String>,
+    #[arg(long, env = "GOLIATH_CLIENT_ID")]
+    pub client_id: Option<String>,
+    #[arg(long, env = "GOLIATH_SECRET_KEY", hide_env_values = true)]
+    pub key: Option<String>,
+    #[arg(long, env = "GOLIATH_WIRE_ENCODING", value_enum)]
+    pub wire_encoding: Option<WireEncoding>,
+    #[command(flatten)]
+    pub tls: TlsOverrides,
+}
+
+/// goliath/client.toml in the platform's per-user config directory
+fn default_config_path() -> Result<PathBuf, ConfigError> {
+    env::var_os("XDG_CONFIG_HOME")
+        .or_else(|| env::var_os("APPDATA"))
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|config_dir| config_dir.join("goliath").join(CONFIG_FILE))
+        .ok_or(ConfigError::NoConfigDir)
+}
+
+impl ApplicationConfig {
+    /// A missing config file is not an error, the defaults are used and the file gets created on save
+    pub fn load(overrides: ConfigOverrides) -> Result<Self, ConfigError> {
+        let path = match overrides.config.clone() {
+            Some(path) => path,
+            None => default_config_path()?,
+        };
+
+        let mut config = match fs::read_to_string(&path) {
+            Ok(contents) => toml::from_str(&contents)
+                .map_err(|err| ConfigError::Malformed(path.clone(), err))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(err) => return Err(ConfigError::Io(path, err)),
+        };
+        config.path = path;
+        config.apply(overrides);
+
+        Ok(config)
+    }
+
+    fn apply(&mut self, overrides: ConfigOverrides) {
+        if let Some(ws_address) = overrides.server {
+            self.ws_address = ws_address;
+        }
+        if let Some(client_id) = overrides.client_id {
+            self.client_id = client_id;
+        }
+        if let Some(key) = overrides.key {
+            self.key = key;
+        }
+        if let Some(wire_encoding) = overrides.wire_encoding {
+            self.wire_encoding = wire_encoding;
+        }
+
+        overrides
+            .tls
+            .apply(&mut self.server_verification, &mut self.client_certificate);
+    }
+
+    /// None until the settings say how to verify the backend
+    pub fn connection_options(&self) -> Option<ConnectionOptions> {
+        Some(ConnectionOptions {
+            address: self.ws_address.clone(),
+            id: self.client_id.clone(),
+            node_type: NodeType::Client,
+            credentials: match &self.client_certificate {
+                Some(client_certificate) => {
+                    NodeCredentials::Certificate(client_certificate.clone())
+                }
+                None => NodeCredentials::SecretKey(self.key.clone()),
+            },
+            encoding: self.wire_encoding,
+            server_verification: self.server_verification.clone()?,
+            backoff: Backoff::default(),
+            heartbeat: HeartbeatPolicy::default(),
+        })
+    }
+
+    /// The file holds the secret key, so it is only readable by its owner
+    pub fn save(&self) -> Result<(), ConfigError> {
+        let io_error = |err| ConfigError::Io(self.path.clone(), err);
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).map_err(io_error)?;
+        }
+
+        let contents = toml::to_string_pretty(self)?;
+        let tmp_path = self.path.with_extension("tmp");
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut file = options.open(&tmp_path).map_err(io_error)?;
+        file.write_all(contents.as_bytes())
+            .and_then(|_| file.sync_all())
+            .and_then(|_| fs::rename(&tmp_path, &self.path))
+            .map_err(io_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ApplicationConfig, ConfigOverrides};
+    use goliath_common::security::ServerVerification;
+
+    #[test]
+    fn test_saved_config_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("client.toml");
+        let overrides = || ConfigOverrides {
+            config: Some(path.clone()),
+            ..Default::default()
+        };
+
+        let mut config = ApplicationConfig::load(overrides()).unwrap();
+        assert_eq!(config.server_verification, None);
+
+        config.client_id = "EmilyClient".to_string();
+        config.server_verification = Some(ServerVerification::Fingerprint("abcd".to_string()));
+        config.save().unwrap();
+        assert_eq!(ApplicationConfig::load(overrides()).unwrap(), config);
+
+        let overridden = ApplicationConfig::load(ConfigOverrides {
+            client_id: Some("OtherClient".to_string()),
+            ..overrides()
+        })
+        .unwrap();
+        assert_eq!(overridden.client_id, "OtherClient");
+    }
 }
@@ -1,4 +1,5 @@
 use application_state::{ApplicationState, ApplicationStateTrait, InitState};
+use clap::Parser;
 use config::ApplicationConfig;
 use eframe::{egui, glow, App, Frame, NativeOptions, Renderer, Theme};
 use goliath_common::logging::setup_logger;
@@ -6,9 +7,15 @@ use std::sync::Arc;
 
 mod application_state;
 mod config;
-mod types;
 mod utils;
 
+#[derive(Parser)]
+#[command(about = "Goliath tank client")]
+struct Cli {
+    #[command(flatten)]
+    config: config::ConfigOverrides,
+}
+
 struct GoliathClientApp {
     config: ApplicationConfig,
     rt: tokio::runtime::Runtime,
@@ -16,10 +23,9 @@ struct GoliathClientApp {
 }
 
 impl GoliathClientApp {
-    fn new(_gl: &Arc<glow::Context>) -> Self {
+    fn new(_gl: &Arc<glow::Context>, config: ApplicationConfig) -> Self {
         // Do stuff with glow context here
 
-        let ws_address = "localhost:8555".to_string();
         let rt = tokio::runtime::Builder::new_multi_thread()
             .enable_all()
             .build()
@@ -27,11 +33,7 @@ impl GoliathClientApp {
         Self {
             application_state: ApplicationState::Init(InitState::new()),
             rt,
-            config: ApplicationConfig {
-                ws_address,
-                client_id: "EmilyClient".to_string(),
-                key: "EmilyClientSecret".to_string(),
-            },
+            config,
         }
     }
 }
@@ -40,7 +42,7 @@ impl App for GoliathClientApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut Frame) {
         ctx.request_repaint();
 
-        if let Some(new_state) = self.application_state.update(&self.rt, &self.config) {
+        if let Some(new_state) = self.application_state.update(&self.rt, &mut self.config) {
             log::debug!("Setting new application state to {new_state:?}");
             self.application_state = new_state;
         }
@@ -57,8 +59,17 @@ impl App for GoliathClientApp {
 }
 
 fn main() -> eframe::Result<()> {
+    let cli = Cli::parse();
     setup_logger();
 
+    let config = match ApplicationConfig::load(cli.config) {
+        Ok(config) => config,
+        Err(err) => {
+            log::error!("{err}");
+            std::process::exit(1);
+        }
+    };
+
     eframe::run_native(
         "Goliath Tank Client",
         NativeOptions {
@@ -76,6 +87,7 @@ fn main() -> eframe::Result<()> {
             utils::ui_utils::generate_font_cache(cc);
             Box::new(GoliathClientApp::new(
                 cc.gl.as_ref().expect("No GL context available"),
+                config,
             ))
         }),
     )
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub enum NodeType {
@@ -7,14 +8,40 @@ pub enum NodeType {
     Vehicle,
 }
 
+/// How the backend holds on to a node's secret
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum SecretKey {
+    /// The base64 secret exactly as the node knows it, required for legacy authentication
+    Plain(String),
+    /// The output of `security::derive_node_key`, this is all the credential store keeps at rest
+    Derived(String),
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Client {
     pub id: String,
-    pub secret_key: String,
+    pub secret_key: SecretKey,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Vehicle {
     pub id: String,
-    pub secret_key: String,
+    pub secret_key: SecretKey,
+}
+
+pub trait NaiveDb {
+    fn get_client(&self, id: &str) -> Option<&Client>;
+
+    fn get_client_mut(&mut self, id: &str) -> Option<&mut Client>;
+
+    fn get_vehicle(&self, id: &str) -> Option<&Vehicle>;
+
+    fn get_vehicle_mut(&mut self, id: &str) -> Option<&mut Vehicle>;
+}
+
+pub fn unix_timestamp_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went like 50 years backwards")
+        .as_millis()
 }
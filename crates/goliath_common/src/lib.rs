@@ -1,10 +1,11 @@
 pub mod core;
 pub mod logging;
+pub mod protocol;
 pub mod security;
 pub mod websocket;
 
-#[cfg(feature = "development")]
-pub mod dev;
+#[cfg(test)]
+mod test_utils;
 
 pub type ClientConnection =
     tokio_tungstenite::WebSocketStream<tokio_rustls::server::TlsStream<tokio::net::TcpStream>>;
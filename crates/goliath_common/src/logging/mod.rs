@@ -2,8 +2,13 @@ use log::LevelFilter;
 use std::io::Write;
 
 pub fn setup_logger() {
+    setup_logger_with_level(LevelFilter::Info);
+}
+
+/// `RUST_LOG` still takes precedence over `level`
+pub fn setup_logger_with_level(level: LevelFilter) {
     env_logger::builder()
-        .filter_level(LevelFilter::Info)
+        .filter_level(level)
         .filter_module("rustls", LevelFilter::Off)
         .filter_module("tungstenite", LevelFilter::Off)
         .filter_module("tokio_tungstenite", LevelFilter::Off)
@@ -0,0 +1,84 @@
+use serde::{Deserialize, Serialize};
+
+/// Differential drive command for the tracks, values are normalized to [-1.0, 1.0]
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct DriveCommand {
+    pub left_track: f32,
+    pub right_track: f32,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Telemetry {
+    pub timestamp: u128,
+    pub left_track: f32,
+    pub right_track: f32,
+    pub battery_voltage: Option<f32>,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct Heartbeat {
+    pub timestamp: u128,
+}
+
+/// Whether a vehicle acts on drive commands, it falls back to `Failsafe` as soon as its commands stop
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum SafetyStatus {
+    Armed,
+    /// The tracks are stopped, and stay stopped until the client re-arms the vehicle
+    Failsafe {
+        reason: String,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum Safety {
+    /// Sent by the vehicle when a session starts, and whenever its status changes
+    Status(SafetyStatus),
+    /// Sent by the client to let a vehicle in failsafe drive again
+    Rearm,
+}
+
+/// Sent by the backend to every node right before it goes away, vehicles stop and enter failsafe
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ShutdownNotice {
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum SessionControl {
+    /// Sent by a client that wants to take control of a vehicle
+    Request { vehicle_id: String },
+    /// Sent by the backend to both sides once the session is established
+    Started { session_id: String, peer_id: String },
+    /// Sent by either side to voluntarily leave the session
+    End,
+    /// Sent by the backend when the session has been torn down
+    Ended { session_id: String, reason: String },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct VehicleInfo {
+    pub id: String,
+    /// Unix timestamp, in milliseconds, of when the vehicle registered
+    pub online_since: u128,
+    pub in_session: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum Discovery {
+    /// Sent by a client to ask for the current vehicle roster
+    ListVehicles,
+    /// The backend's answer to `ListVehicles`
+    VehicleList(Vec<VehicleInfo>),
+    /// Pushed to every client when a vehicle comes online
+    VehicleJoined(VehicleInfo),
+    /// Pushed to every client when a vehicle enters or leaves a session
+    VehicleUpdated(VehicleInfo),
+    /// Pushed to every client when a vehicle goes offline
+    VehicleLeft { vehicle_id: String },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ErrorMessage {
+    pub msg: String,
+}
@@ -0,0 +1,171 @@
+mod messages;
+
+pub use messages::{
+    Discovery, DriveCommand, ErrorMessage, Heartbeat, Safety, SafetyStatus, SessionControl,
+    ShutdownNotice, Telemetry, VehicleInfo,
+};
+
+use crate::security::{RegistrationChallenge, RegistrationRequest, RegistrationResponse};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio_tungstenite::tungstenite::Message;
+
+/// Bump this whenever the layout of `GoliathMessage` changes in a non-compatible way
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// How a node wants its frames encoded, declared during registration.
+/// JSON is sent as text frames and is easy to inspect, bincode is sent as binary frames
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq, ValueEnum)]
+pub enum WireEncoding {
+    #[default]
+    Json,
+    Bincode,
+}
+
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("Malformed message: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Malformed message: {0}")]
+    Bincode(#[from] bincode::Error),
+    #[error("Unsupported protocol version {0}, expected {PROTOCOL_VERSION}")]
+    UnsupportedVersion(u16),
+    #[error("Unexpected websocket frame type")]
+    UnexpectedFrame,
+}
+
+/// Every frame that goes over the wire, in either direction, is one of these
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum GoliathMessage {
+    Challenge(RegistrationChallenge),
+    Registration(RegistrationRequest),
+    RegistrationResponse(RegistrationResponse),
+    Drive(DriveCommand),
+    Telemetry(Telemetry),
+    Heartbeat(Heartbeat),
+    Session(SessionControl),
+    Discovery(Discovery),
+    Error(ErrorMessage),
+    Safety(Safety),
+    Shutdown(ShutdownNotice),
+}
+
+#[derive(Serialize)]
+struct OutgoingEnvelope<'a> {
+    version: u16,
+    message: &'a GoliathMessage,
+}
+
+// The version is checked separately, through `VersionProbe`, before the message itself is parsed.
+// It still has to be declared here since bincode relies on the field order
+#[derive(Deserialize)]
+struct IncomingEnvelope {
+    #[serde(rename = "version")]
+    _version: u16,
+    message: GoliathMessage,
+}
+
+#[derive(Deserialize)]
+struct VersionProbe {
+    version: u16,
+}
+
+fn check_version(probe: VersionProbe) -> Result<(), ProtocolError> {
+    if probe.version == PROTOCOL_VERSION {
+        Ok(())
+    } else {
+        Err(ProtocolError::UnsupportedVersion(probe.version))
+    }
+}
+
+impl GoliathMessage {
+    pub fn error(msg: impl ToString) -> Self {
+        Self::Error(ErrorMessage {
+            msg: msg.to_string(),
+        })
+    }
+
+    pub fn to_ws_message(&self, encoding: WireEncoding) -> Result<Message, ProtocolError> {
+        let envelope = OutgoingEnvelope {
+            version: PROTOCOL_VERSION,
+            message: self,
+        };
+
+        Ok(match encoding {
+            WireEncoding::Json => Message::Text(serde_json::to_string(&envelope)?),
+            WireEncoding::Bincode => Message::Binary(bincode::serialize(&envelope)?),
+        })
+    }
+
+    /// Accepts both encodings regardless of what was negotiated, the frame type tells them apart
+    pub fn from_ws_message(msg: &Message) -> Result<Self, ProtocolError> {
+        match msg {
+            Message::Text(text) => {
+                check_version(serde_json::from_str(text)?)?;
+                Ok(serde_json::from_str::<IncomingEnvelope>(text)?.message)
+            }
+            Message::Binary(bytes) => {
+                check_version(bincode::deserialize(bytes)?)?;
+                Ok(bincode::deserialize::<IncomingEnvelope>(bytes)?.message)
+            }
+            _ => Err(ProtocolError::UnexpectedFrame),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        DriveCommand, GoliathMessage, ProtocolError, Safety, SafetyStatus, SessionControl,
+        ShutdownNotice, Telemetry, WireEncoding,
+    };
+    use tokio_tungstenite::tungstenite::Message;
+
+    #[test]
+    fn test_round_trip() {
+        let messages = [
+            GoliathMessage::Drive(DriveCommand {
+                left_track: 0.5,
+                right_track: -1.0,
+            }),
+            GoliathMessage::Session(SessionControl::Request {
+                vehicle_id: "EmilyVehicle".to_string(),
+            }),
+            GoliathMessage::Telemetry(Telemetry {
+                timestamp: 1_700_000_000_000,
+                battery_voltage: Some(11.8),
+                ..Default::default()
+            }),
+            GoliathMessage::Safety(Safety::Status(SafetyStatus::Failsafe {
+                reason: "No drive command received".to_string(),
+            })),
+            GoliathMessage::Shutdown(ShutdownNotice {
+                reason: "Backend is shutting down".to_string(),
+            }),
+        ];
+
+        for encoding in [WireEncoding::Json, WireEncoding::Bincode] {
+            for message in messages.iter() {
+                let ws_message = message.to_ws_message(encoding).unwrap();
+                let decoded = GoliathMessage::from_ws_message(&ws_message).unwrap();
+                assert_eq!(message, &decoded);
+            }
+        }
+    }
+
+    #[test]
+    fn test_rejects_other_versions() {
+        let msg = Message::Text(r#"{"version":0,"message":{"Heartbeat":{"timestamp":0}}}"#.into());
+        assert!(matches!(
+            GoliathMessage::from_ws_message(&msg),
+            Err(ProtocolError::UnsupportedVersion(0))
+        ));
+
+        let msg = Message::Binary(bincode::serialize(&(0u16, 0u8)).unwrap());
+        assert!(matches!(
+            GoliathMessage::from_ws_message(&msg),
+            Err(ProtocolError::UnsupportedVersion(0))
+        ));
+    }
+}
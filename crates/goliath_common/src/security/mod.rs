@@ -1,5 +1,15 @@
 mod registration;
+mod server_verification;
 mod verifier;
 
-pub use registration::{generate_registration_hash, RegistrationRequest};
+pub use registration::{
+    derive_node_key, generate_registration_hash, generate_registration_mac, AuthScheme,
+    RegistrationChallenge, RegistrationError, RegistrationRequest, RegistrationResponse,
+    RegistrationStatus,
+};
+pub use server_verification::{
+    ClientCertificate, ServerVerification, ServerVerificationError, TlsOverrides,
+};
+#[cfg(feature = "development")]
 pub use verifier::NoVerifier;
+pub use verifier::{certificate_fingerprint, FingerprintMismatch, PinnedVerifier};
@@ -1,21 +1,48 @@
-use crate::core::NodeType;
-use crate::dev::NaiveDb;
+use crate::core::{NaiveDb, NodeType, SecretKey};
+use crate::protocol::WireEncoding;
 use base64::{DecodeError, Engine};
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
-use std::error::Error;
-use std::sync::Arc;
+use sha2::Sha256;
 use thiserror::Error;
-use tokio::sync::Mutex;
 
-#[derive(Clone, Serialize, Deserialize)]
+// Prepended to every MAC'd payload so it can never be confused with anything else we sign
+const REGISTRATION_DOMAIN: &str = "goliath-registration-v2";
+const NODE_KEY_DOMAIN: &str = "goliath-node-key-v1";
+
+/// Which scheme was used to produce `RegistrationRequest::hash`
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize, PartialEq)]
+pub enum AuthScheme {
+    /// `sha256(id + timestamp + key)`, only kept around until every node is updated
+    #[default]
+    LegacySha256,
+    /// HMAC-SHA256 over a canonical encoding of the request and the server's challenge
+    HmacSha256,
+    /// The node presented a certificate issued by the backend's node CA during the TLS handshake,
+    /// `hash` is left empty
+    ClientCertificate,
+}
+
+/// Sent by the backend as soon as a node connects, the nonce is only valid for that connection
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct RegistrationChallenge {
+    pub nonce: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct RegistrationRequest {
     pub id: String,
     pub timestamp: u128,
     pub hash: String,
     pub node_type: NodeType,
+    /// The encoding the backend should use for every frame it sends to this node
+    #[serde(default)]
+    pub encoding: WireEncoding,
+    #[serde(default)]
+    pub auth_scheme: AuthScheme,
 }
 
-#[derive(Clone, Debug, Error)]
+#[derive(Clone, Debug, Error, Serialize, Deserialize, PartialEq)]
 pub enum RegistrationError {
     #[error("No such client found")]
     ClientNotFound,
@@ -25,41 +52,128 @@ pub enum RegistrationError {
     UnknownNodeType,
     #[error("Registration failed: mismatching hash")]
     MismatchedHash,
+    #[error("Registration failed: timestamp is outside the acceptance window")]
+    StaleTimestamp,
+    #[error("Registration failed: request was already used")]
+    ReplayedRequest,
+    #[error("Registration failed: stored secret key is not valid base64")]
+    InvalidSecretKey,
+    #[error("Expected a registration request")]
+    InvalidRequest,
+    #[error("Registration failed: legacy authentication is disabled")]
+    LegacyAuthDisabled,
+    #[error("Registration failed: no client certificate was presented")]
+    MissingCertificate,
+    #[error("Registration failed: request does not match the client certificate")]
+    CertificateMismatch,
+    #[error("Registration failed: a node with this id is already connected")]
+    DuplicateId,
+    /// Sent to a registered node after the fact, when a newer connection took over its id
+    #[error("Another node registered with this id")]
+    Displaced,
 }
 
 impl RegistrationRequest {
-    #[cfg(feature = "development")]
     pub fn verify_hash<DB: NaiveDb>(
         &self,
-        cache_db: Arc<Mutex<DB>>,
-    ) -> Result<NodeType, Box<dyn Error>> {
+        db: &DB,
+        nonce: &str,
+    ) -> Result<NodeType, RegistrationError> {
         let secret_key = match self.node_type {
-            NodeType::Client => cache_db
-                .blocking_lock()
+            NodeType::Client => db
                 .get_client(&self.id)
-                .map(|client| client.secret_key.clone())
+                .map(|client| &client.secret_key)
                 .ok_or(RegistrationError::ClientNotFound),
-            NodeType::Vehicle => cache_db
-                .blocking_lock()
-                .get_client(&self.id)
-                .map(|client| client.secret_key.clone())
+            NodeType::Vehicle => db
+                .get_vehicle(&self.id)
+                .map(|vehicle| &vehicle.secret_key)
                 .ok_or(RegistrationError::VehicleNotFound),
             _ => Err(RegistrationError::UnknownNodeType),
         }?;
 
-        let generated_hash = generate_registration_hash(&self.id, self.timestamp, &secret_key)?;
-        if generated_hash == self.hash {
-            Ok(self.node_type)
-        } else {
-            Err(RegistrationError::MismatchedHash.into())
+        match (self.auth_scheme, secret_key) {
+            (AuthScheme::LegacySha256, SecretKey::Plain(secret_key)) => {
+                let generated_hash =
+                    generate_registration_hash(&self.id, self.timestamp, secret_key)
+                        .map_err(|_| RegistrationError::InvalidSecretKey)?;
+                if generated_hash == self.hash {
+                    Ok(self.node_type)
+                } else {
+                    Err(RegistrationError::MismatchedHash)
+                }
+            }
+            // The legacy hash needs the secret itself, which we no longer have
+            (AuthScheme::LegacySha256, SecretKey::Derived(_)) => {
+                Err(RegistrationError::LegacyAuthDisabled)
+            }
+            // Only the TLS layer can vouch for these
+            (AuthScheme::ClientCertificate, _) => Err(RegistrationError::MissingCertificate),
+            (AuthScheme::HmacSha256, secret_key) => {
+                let derived_key = match secret_key {
+                    SecretKey::Plain(secret_key) => derive_node_key(&self.id, secret_key)
+                        .map_err(|_| RegistrationError::InvalidSecretKey)?,
+                    SecretKey::Derived(derived_key) => derived_key.clone(),
+                };
+                let received_mac = base64::engine::general_purpose::STANDARD
+                    .decode(&self.hash)
+                    .map_err(|_| RegistrationError::MismatchedHash)?;
+
+                // verify_slice compares in constant time
+                registration_mac(
+                    &self.id,
+                    self.node_type,
+                    self.timestamp,
+                    nonce,
+                    &derived_key,
+                )
+                .map_err(|_| RegistrationError::InvalidSecretKey)?
+                .verify_slice(&received_mac)
+                .map(|_| self.node_type)
+                .map_err(|_| RegistrationError::MismatchedHash)
+            }
         }
     }
 }
 
-#[derive(Clone, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum RegistrationStatus {
+    Accepted,
+    Rejected(RegistrationError),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct RegistrationResponse {
-    pub status: String,
+    pub status: RegistrationStatus,
     pub msg: String,
+    /// Only set when the node was registered under a different id than the one it asked for
+    #[serde(default)]
+    pub assigned_id: Option<String>,
+}
+
+impl RegistrationResponse {
+    pub fn accepted() -> Self {
+        Self {
+            status: RegistrationStatus::Accepted,
+            msg: "Registered successfully".to_string(),
+            assigned_id: None,
+        }
+    }
+
+    pub fn accepted_as(id: &str) -> Self {
+        Self {
+            status: RegistrationStatus::Accepted,
+            msg: format!("Registered successfully as {id}"),
+            assigned_id: Some(id.to_string()),
+        }
+    }
+
+    pub fn rejected(err: RegistrationError) -> Self {
+        Self {
+            msg: err.to_string(),
+            status: RegistrationStatus::Rejected(err),
+            assigned_id: None,
+        }
+    }
 }
 
 pub fn generate_registration_hash(
@@ -76,3 +190,148 @@ pub fn generate_registration_hash(
             out
         })
 }
+
+// Every field is length prefixed, so no two distinct inputs can ever produce the same payload
+fn canonical_payload(fields: &[&[u8]]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(128);
+    for field in fields {
+        payload.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        payload.extend_from_slice(field);
+    }
+    payload
+}
+
+fn keyed_mac(key: &[u8], fields: &[&[u8]]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take a key of any size");
+    mac.update(&canonical_payload(fields));
+    mac
+}
+
+/// Derives the per-node key that is actually used for authentication, from the node's base64 secret.
+/// This is what the backend stores instead of the secret, it can't be reversed into the secret,
+/// but it is enough to impersonate the node against this backend, so it must still be protected
+pub fn derive_node_key(id: &str, secret_key: &str) -> Result<String, DecodeError> {
+    let secret_key = base64::engine::general_purpose::STANDARD.decode(secret_key)?;
+    let mac = keyed_mac(&secret_key, &[NODE_KEY_DOMAIN.as_bytes(), id.as_bytes()]);
+    Ok(base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes()))
+}
+
+fn registration_mac(
+    id: &str,
+    node_type: NodeType,
+    timestamp: u128,
+    nonce: &str,
+    derived_key: &str,
+) -> Result<Hmac<Sha256>, DecodeError> {
+    let derived_key = base64::engine::general_purpose::STANDARD.decode(derived_key)?;
+    let node_type = format!("{node_type:?}");
+
+    Ok(keyed_mac(
+        &derived_key,
+        &[
+            REGISTRATION_DOMAIN.as_bytes(),
+            id.as_bytes(),
+            node_type.as_bytes(),
+            &timestamp.to_be_bytes(),
+            nonce.as_bytes(),
+        ],
+    ))
+}
+
+/// Answers the backend's `RegistrationChallenge`, the result goes in `RegistrationRequest::hash`
+pub fn generate_registration_mac(
+    id: &str,
+    node_type: NodeType,
+    timestamp: u128,
+    nonce: &str,
+    secret_key: &str,
+) -> Result<String, DecodeError> {
+    let derived_key = derive_node_key(id, secret_key)?;
+    registration_mac(id, node_type, timestamp, nonce, &derived_key)
+        .map(|mac| base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        derive_node_key, generate_registration_mac, AuthScheme, RegistrationError,
+        RegistrationRequest,
+    };
+    use crate::core::{Client, NaiveDb, NodeType, SecretKey, Vehicle};
+    use crate::protocol::WireEncoding;
+
+    // "EmilyClientSecret", base64 encoded
+    const KEY: &str = "RW1pbHlDbGllbnRTZWNyZXQ=";
+
+    struct TestDb(Client);
+
+    impl NaiveDb for TestDb {
+        fn get_client(&self, id: &str) -> Option<&Client> {
+            (self.0.id == id).then_some(&self.0)
+        }
+
+        fn get_client_mut(&mut self, id: &str) -> Option<&mut Client> {
+            (self.0.id == id).then_some(&mut self.0)
+        }
+
+        fn get_vehicle(&self, _id: &str) -> Option<&Vehicle> {
+            None
+        }
+
+        fn get_vehicle_mut(&mut self, _id: &str) -> Option<&mut Vehicle> {
+            None
+        }
+    }
+
+    fn test_db(secret_key: SecretKey) -> TestDb {
+        TestDb(Client {
+            id: "EmilyClient".to_string(),
+            secret_key,
+        })
+    }
+
+    fn hmac_request(nonce: &str) -> RegistrationRequest {
+        RegistrationRequest {
+            id: "EmilyClient".to_string(),
+            timestamp: 1_700_000_000_000,
+            hash: generate_registration_mac(
+                "EmilyClient",
+                NodeType::Client,
+                1_700_000_000_000,
+                nonce,
+                KEY,
+            )
+            .unwrap(),
+            node_type: NodeType::Client,
+            encoding: WireEncoding::Json,
+            auth_scheme: AuthScheme::HmacSha256,
+        }
+    }
+
+    #[test]
+    fn test_verify_hmac() {
+        let request = hmac_request("nonce");
+        let derived_key = derive_node_key("EmilyClient", KEY).unwrap();
+
+        for db in [
+            test_db(SecretKey::Plain(KEY.to_string())),
+            test_db(SecretKey::Derived(derived_key)),
+        ] {
+            assert_eq!(request.verify_hash(&db, "nonce"), Ok(NodeType::Client));
+
+            // A response to one challenge is worthless for any other challenge
+            assert_eq!(
+                request.verify_hash(&db, "other nonce"),
+                Err(RegistrationError::MismatchedHash)
+            );
+        }
+    }
+
+    #[test]
+    fn test_unambiguous_field_boundaries() {
+        assert_ne!(
+            generate_registration_mac("Emily1", NodeType::Client, 23, "nonce", KEY),
+            generate_registration_mac("Emily", NodeType::Client, 123, "nonce", KEY)
+        );
+    }
+}
@@ -0,0 +1,296 @@
+use crate::security::verifier::{certificate_fingerprint, PinnedVerifier};
+use clap::Args;
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer},
+    ClientConfig, RootCertStore,
+};
+use serde::{Deserialize, Serialize};
+use std::{fs::File, io::BufReader, path::PathBuf, sync::Arc};
+use thiserror::Error;
+
+/// How a node decides whether it is really talking to our backend
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub enum ServerVerification {
+    /// Only the certificate with this hex encoded SHA-256 fingerprint is accepted
+    Fingerprint(String),
+    /// Only the certificate stored in this PEM file is accepted
+    PinnedCertificate(PathBuf),
+    /// Any certificate for the right name that chains up to a CA in this PEM file is accepted
+    PrivateCa(PathBuf),
+    /// Accepts any certificate at all, refused unless built with the `development` feature
+    Insecure,
+}
+
+/// A certificate issued by the backend's node CA, which identifies the node without a secret key
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct ClientCertificate {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// The TLS flags the client and the vehicle share, flattened into their own overrides
+#[derive(Debug, Default, Args)]
+pub struct TlsOverrides {
+    /// Only accept the backend certificate with this SHA-256 fingerprint
+    #[arg(long, env = "GOLIATH_SERVER_FINGERPRINT")]
+    pub server_fingerprint: Option<String>,
+    /// Only accept the backend certificate stored in this PEM file
+    #[arg(long, env = "GOLIATH_SERVER_CERT")]
+    pub server_cert: Option<PathBuf>,
+    /// Accept backend certificates issued by the CA in this PEM file
+    #[arg(long, env = "GOLIATH_SERVER_CA")]
+    pub server_ca: Option<PathBuf>,
+    /// Accept any backend certificate, only honoured by builds with the `development` feature
+    #[arg(long, env = "GOLIATH_INSECURE_SKIP_VERIFY")]
+    pub insecure_skip_verify: bool,
+    /// Register through a node certificate, requires --client-cert-key too
+    #[arg(long, env = "GOLIATH_CLIENT_CERT", requires = "client_cert_key")]
+    pub client_cert: Option<PathBuf>,
+    #[arg(long, env = "GOLIATH_CLIENT_KEY", requires = "client_cert")]
+    pub client_cert_key: Option<PathBuf>,
+}
+
+impl TlsOverrides {
+    /// When several ways of verifying the backend are given, the strictest one wins
+    pub fn apply(
+        self,
+        server_verification: &mut Option<ServerVerification>,
+        client_certificate: &mut Option<ClientCertificate>,
+    ) {
+        if let Some(fingerprint) = self.server_fingerprint {
+            *server_verification = Some(ServerVerification::Fingerprint(fingerprint));
+        } else if let Some(path) = self.server_cert {
+            *server_verification = Some(ServerVerification::PinnedCertificate(path));
+        } else if let Some(path) = self.server_ca {
+            *server_verification = Some(ServerVerification::PrivateCa(path));
+        } else if self.insecure_skip_verify {
+            *server_verification = Some(ServerVerification::Insecure);
+        }
+
+        if let (Some(cert), Some(key)) = (self.client_cert, self.client_cert_key) {
+            *client_certificate = Some(ClientCertificate { cert, key });
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ServerVerificationError {
+    #[error("Could not read certificates from {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("No certificate found in {0}")]
+    NoCertificate(PathBuf),
+    #[error("No private key found in {0}")]
+    NoPrivateKey(PathBuf),
+    #[error("Invalid client certificate: {0}")]
+    InvalidClientCertificate(rustls::Error),
+    #[error("Invalid CA certificate: {0}")]
+    InvalidCa(rustls::Error),
+    #[error("Could not build the TLS configuration: {0}")]
+    Tls(rustls::Error),
+    #[error("Could not build the certificate verifier: {0}")]
+    Verifier(rustls::client::VerifierBuilderError),
+    #[error("Certificate verification can only be skipped in development builds")]
+    InsecureNotAllowed,
+}
+
+fn load_certificates(
+    path: &PathBuf,
+) -> Result<Vec<CertificateDer<'static>>, ServerVerificationError> {
+    let file = File::open(path).map_err(|err| ServerVerificationError::Io(path.clone(), err))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| ServerVerificationError::Io(path.clone(), err))?;
+
+    if certificates.is_empty() {
+        Err(ServerVerificationError::NoCertificate(path.clone()))
+    } else {
+        Ok(certificates)
+    }
+}
+
+fn load_private_key(path: &PathBuf) -> Result<PrivateKeyDer<'static>, ServerVerificationError> {
+    let file = File::open(path).map_err(|err| ServerVerificationError::Io(path.clone(), err))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|err| ServerVerificationError::Io(path.clone(), err))?
+        .ok_or_else(|| ServerVerificationError::NoPrivateKey(path.clone()))
+}
+
+impl ServerVerification {
+    pub fn client_config(
+        &self,
+        client_certificate: Option<&ClientCertificate>,
+    ) -> Result<ClientConfig, ServerVerificationError> {
+        let provider: Arc<CryptoProvider> = Arc::new(ring::default_provider());
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(ServerVerificationError::Tls)?;
+
+        let config = match self {
+            Self::Fingerprint(fingerprint) => {
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(PinnedVerifier::new(
+                        fingerprint,
+                        provider,
+                    )))
+            }
+            Self::PinnedCertificate(path) => {
+                // The first certificate in the file is the end entity one
+                let fingerprint = certificate_fingerprint(load_certificates(path)?[0].as_ref());
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(PinnedVerifier::new(
+                        &fingerprint,
+                        provider,
+                    )))
+            }
+            Self::PrivateCa(path) => {
+                let mut roots = RootCertStore::empty();
+                for certificate in load_certificates(path)? {
+                    roots
+                        .add(certificate)
+                        .map_err(ServerVerificationError::InvalidCa)?;
+                }
+                let verifier = rustls::client::WebPkiServerVerifier::builder_with_provider(
+                    Arc::new(roots),
+                    provider,
+                )
+                .build()
+                .map_err(ServerVerificationError::Verifier)?;
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(verifier)
+            }
+            #[cfg(feature = "development")]
+            Self::Insecure => {
+                log::warn!("Server certificate verification is disabled, this is only fine for development");
+                builder
+                    .dangerous()
+                    .with_custom_certificate_verifier(Arc::new(crate::security::NoVerifier))
+            }
+            #[cfg(not(feature = "development"))]
+            Self::Insecure => return Err(ServerVerificationError::InsecureNotAllowed),
+        };
+
+        match client_certificate {
+            Some(client_certificate) => config
+                .with_client_auth_cert(
+                    load_certificates(&client_certificate.cert)?,
+                    load_private_key(&client_certificate.key)?,
+                )
+                .map_err(ServerVerificationError::InvalidClientCertificate),
+            None => Ok(config.with_no_client_auth()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ServerVerification;
+    use crate::security::verifier::normalize_fingerprint;
+    use crate::security::{FingerprintMismatch, PinnedVerifier};
+    use crate::test_utils::{test_ca, FakeBackend, TestIdentity};
+    use crate::websocket::{goliath_ws_connect, HeartbeatPolicy};
+    use rustls::{
+        client::danger::ServerCertVerifier,
+        crypto::ring,
+        pki_types::{ServerName, UnixTime},
+        CertificateError, Error,
+    };
+    use std::sync::Arc;
+
+    // Whether a node verifying the backend this way gets through the TLS handshake
+    async fn connects(backend_identity: &TestIdentity, verification: ServerVerification) -> bool {
+        let backend = FakeBackend::start(backend_identity).await;
+        let address = format!("wss://{}", backend.address());
+        let accepting = tokio::spawn(async move { backend.try_accept().await });
+
+        let connected =
+            goliath_ws_connect(address, &verification, None, HeartbeatPolicy::default())
+                .await
+                .is_ok();
+        accepting.abort();
+        connected
+    }
+
+    #[test]
+    fn test_fingerprint_formats() {
+        assert_eq!(
+            normalize_fingerprint("AB:cd:01"),
+            normalize_fingerprint("abcd01")
+        );
+        assert!(ServerVerification::Fingerprint("abcd01".to_string())
+            .client_config(None)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_pinned_verifier() {
+        let pinned = TestIdentity::self_signed();
+        let other = TestIdentity::self_signed();
+        let verifier =
+            PinnedVerifier::new(&pinned.fingerprint(), Arc::new(ring::default_provider()));
+        let verify = |identity: &TestIdentity| {
+            verifier.verify_server_cert(
+                &identity.cert,
+                &[],
+                &ServerName::try_from("localhost").unwrap(),
+                &[],
+                UnixTime::now(),
+            )
+        };
+
+        assert!(verify(&pinned).is_ok());
+        match verify(&other) {
+            Err(Error::InvalidCertificate(CertificateError::Other(err))) => {
+                let mismatch = err.0.downcast_ref::<FingerprintMismatch>().unwrap();
+                assert_eq!(mismatch.presented, other.fingerprint());
+                assert_eq!(mismatch.pinned, pinned.fingerprint());
+                assert!(err
+                    .to_string()
+                    .contains("does not match the pinned fingerprint"));
+            }
+            res => panic!("Expected a fingerprint mismatch, got {res:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verification_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = test_ca();
+        let ca_path = dir.path().join("ca.pem");
+        std::fs::write(&ca_path, ca.serialize_pem().unwrap()).unwrap();
+
+        let backend = TestIdentity::signed_by(Some(&ca));
+        let impostor = TestIdentity::self_signed();
+        let pinned_path = dir.path().join("backend.pem");
+        std::fs::write(&pinned_path, &backend.cert_pem).unwrap();
+
+        for verification in [
+            ServerVerification::Fingerprint(backend.fingerprint()),
+            ServerVerification::PinnedCertificate(pinned_path),
+            ServerVerification::PrivateCa(ca_path),
+        ] {
+            assert!(
+                connects(&backend, verification.clone()).await,
+                "{verification:?} turned the backend down"
+            );
+            assert!(
+                !connects(&impostor, verification.clone()).await,
+                "{verification:?} accepted an impostor"
+            );
+        }
+    }
+
+    #[cfg(not(feature = "development"))]
+    #[test]
+    fn test_insecure_needs_development_builds() {
+        use super::ServerVerificationError;
+
+        assert!(matches!(
+            ServerVerification::Insecure.client_config(None),
+            Err(ServerVerificationError::InsecureNotAllowed)
+        ));
+    }
+}
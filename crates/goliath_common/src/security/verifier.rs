@@ -1,12 +1,18 @@
 use rustls::{
     client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider},
     pki_types::{CertificateDer, ServerName, UnixTime},
-    DigitallySignedStruct, Error, SignatureScheme,
+    CertificateError, DigitallySignedStruct, Error, OtherError, SignatureScheme,
 };
+use std::sync::Arc;
+use thiserror::Error;
 
+/// Accepts any certificate whatsoever, only ever usable in development builds
+#[cfg(feature = "development")]
 #[derive(Debug)]
 pub struct NoVerifier;
 
+#[cfg(feature = "development")]
 impl ServerCertVerifier for NoVerifier {
     fn verify_server_cert(
         &self,
@@ -55,3 +61,104 @@ impl ServerCertVerifier for NoVerifier {
         ]
     }
 }
+
+/// The backend presented another certificate than the one that was pinned
+#[derive(Debug, Error)]
+#[error(
+    "Server certificate fingerprint {presented} does not match the pinned fingerprint {pinned}"
+)]
+pub struct FingerprintMismatch {
+    pub presented: String,
+    pub pinned: String,
+}
+
+/// Accepts only the one certificate whose SHA-256 fingerprint was pinned, whatever its issuer or name.
+/// The handshake signatures are still verified, so the server must actually hold the private key
+#[derive(Debug)]
+pub struct PinnedVerifier {
+    fingerprint: String,
+    provider: Arc<CryptoProvider>,
+}
+
+impl PinnedVerifier {
+    /// `fingerprint` is the hex encoded SHA-256 of the certificate's DER encoding
+    pub fn new(fingerprint: &str, provider: Arc<CryptoProvider>) -> Self {
+        Self {
+            fingerprint: normalize_fingerprint(fingerprint),
+            provider,
+        }
+    }
+}
+
+/// Fingerprints are commonly displayed as colon separated uppercase pairs, accept that too
+pub fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| !matches!(c, ':' | ' '))
+        .collect::<String>()
+        .to_lowercase()
+}
+
+pub fn certificate_fingerprint(cert: &[u8]) -> String {
+    sha256::digest(cert)
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let fingerprint = certificate_fingerprint(end_entity.as_ref());
+        if fingerprint == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            let mismatch = FingerprintMismatch {
+                presented: fingerprint,
+                pinned: self.fingerprint.clone(),
+            };
+            // The TLS error that reaches the caller only says the certificate was invalid
+            log::error!("{mismatch}");
+            Err(Error::InvalidCertificate(CertificateError::Other(
+                OtherError(Arc::new(mismatch)),
+            )))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -0,0 +1,138 @@
+use crate::protocol::GoliathMessage;
+use crate::security::{certificate_fingerprint, RegistrationChallenge, RegistrationResponse};
+use crate::ClientConnection;
+use futures_util::{SinkExt, StreamExt};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa};
+use rustls::{
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer},
+    ServerConfig,
+};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+/// A certificate for localhost, along with its key
+pub struct TestIdentity {
+    pub cert: CertificateDer<'static>,
+    pub cert_pem: String,
+    pub key: PrivateKeyDer<'static>,
+}
+
+impl TestIdentity {
+    pub fn self_signed() -> Self {
+        Self::signed_by(None)
+    }
+
+    /// Signed by `ca` when given, self-signed otherwise
+    pub fn signed_by(ca: Option<&Certificate>) -> Self {
+        let cert = Certificate::from_params(CertificateParams::new(vec!["localhost".to_string()]))
+            .unwrap();
+        // Every serialization is signed anew, so the DER has to come from this very PEM
+        let cert_pem = match ca {
+            Some(ca) => cert.serialize_pem_with_signer(ca).unwrap(),
+            None => cert.serialize_pem().unwrap(),
+        };
+
+        let der = rustls_pemfile::certs(&mut cert_pem.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap();
+
+        Self {
+            cert: der,
+            cert_pem,
+            key: PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.serialize_private_key_der())),
+        }
+    }
+
+    pub fn fingerprint(&self) -> String {
+        certificate_fingerprint(self.cert.as_ref())
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![self.cert.clone()], self.key.clone_key())
+            .unwrap();
+        TlsAcceptor::from(Arc::new(config))
+    }
+}
+
+pub fn test_ca() -> Certificate {
+    let mut params = CertificateParams::new(Vec::new());
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "Goliath Test CA");
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    Certificate::from_params(params).unwrap()
+}
+
+/// Stands in for the backend, nodes are only registered when a test says so
+pub struct FakeBackend {
+    pub addr: SocketAddr,
+    tcp_listener: TcpListener,
+    acceptor: TlsAcceptor,
+}
+
+impl FakeBackend {
+    pub async fn start(identity: &TestIdentity) -> Self {
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        Self {
+            addr: tcp_listener.local_addr().unwrap(),
+            tcp_listener,
+            acceptor: identity.acceptor(),
+        }
+    }
+
+    /// The address nodes connect to, the certificate is only valid for localhost
+    pub fn address(&self) -> String {
+        format!("localhost:{}", self.addr.port())
+    }
+
+    pub async fn accept(&self) -> ClientConnection {
+        self.try_accept().await.unwrap()
+    }
+
+    /// None when the node gave up on the TLS handshake or the websocket upgrade
+    pub async fn try_accept(&self) -> Option<ClientConnection> {
+        let (stream, _) = self.tcp_listener.accept().await.ok()?;
+        let tls_stream = self.acceptor.accept(stream).await.ok()?;
+        tokio_tungstenite::accept_async(tls_stream).await.ok()
+    }
+
+    /// Accepts the next node, challenges it and answers its registration with `response`
+    pub async fn accept_registration(&self, response: RegistrationResponse) -> ClientConnection {
+        let mut ws_conn = self.accept().await;
+        send(
+            &mut ws_conn,
+            GoliathMessage::Challenge(RegistrationChallenge {
+                nonce: "nonce".to_string(),
+            }),
+        )
+        .await;
+        loop {
+            if let GoliathMessage::Registration(_) = recv(&mut ws_conn).await {
+                break;
+            }
+        }
+        send(&mut ws_conn, GoliathMessage::RegistrationResponse(response)).await;
+        ws_conn
+    }
+}
+
+pub async fn send(ws_conn: &mut ClientConnection, message: GoliathMessage) {
+    ws_conn
+        .send(message.to_ws_message(Default::default()).unwrap())
+        .await
+        .unwrap();
+}
+
+/// Skips over frames that aren't messages, such as pings
+pub async fn recv(ws_conn: &mut ClientConnection) -> GoliathMessage {
+    loop {
+        let frame = ws_conn.next().await.unwrap().unwrap();
+        if let Ok(message) = GoliathMessage::from_ws_message(&frame) {
+            return message;
+        }
+    }
+}
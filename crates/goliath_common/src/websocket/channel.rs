@@ -0,0 +1,294 @@
+use crate::protocol::GoliathMessage;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::mpsc::error::{SendError, TryRecvError, TrySendError};
+use tokio::sync::{Notify, Semaphore, TryAcquireError};
+
+/// Decides what happens to a message when the receiving end falls behind
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MessageClass {
+    /// Latest wins, a newer command replaces the one still waiting
+    Drive,
+    /// Latest wins, a newer reading replaces the one still waiting
+    Telemetry,
+    /// Lossless, the sender waits for room instead
+    Control,
+}
+
+impl MessageClass {
+    pub fn of(message: &GoliathMessage) -> Self {
+        match message {
+            GoliathMessage::Drive(_) => Self::Drive,
+            GoliathMessage::Telemetry(_) => Self::Telemetry,
+            _ => Self::Control,
+        }
+    }
+
+    /// Whether only the newest message of this class is worth delivering
+    pub fn is_latest_wins(self) -> bool {
+        self != Self::Control
+    }
+}
+
+/// How many messages of each class never reached the receiver
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DroppedFrames {
+    pub drive: u64,
+    pub telemetry: u64,
+    pub control: u64,
+}
+
+impl DroppedFrames {
+    pub fn total(&self) -> u64 {
+        self.drive + self.telemetry + self.control
+    }
+
+    fn count(&mut self, class: MessageClass) {
+        match class {
+            MessageClass::Drive => self.drive += 1,
+            MessageClass::Telemetry => self.telemetry += 1,
+            MessageClass::Control => self.control += 1,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    queue: VecDeque<(MessageClass, GoliathMessage)>,
+    dropped: DroppedFrames,
+    senders: usize,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    // One permit per free slot for control messages, closed once the receiver is gone
+    control_slots: Semaphore,
+    // Woken whenever a message is queued, or the last sender went away
+    message_ready: Notify,
+}
+
+impl Shared {
+    fn state(&self) -> MutexGuard<'_, State> {
+        // Nothing in here can panic while the lock is held, but a poisoned queue is still usable
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn push(&self, class: MessageClass, message: GoliathMessage) {
+        let mut state = self.state();
+        // The newer message goes to the back, it must not overtake control messages queued before it
+        let waiting = class
+            .is_latest_wins()
+            .then(|| state.queue.iter().position(|(queued, _)| *queued == class))
+            .flatten();
+        if let Some(waiting) = waiting {
+            state.queue.remove(waiting);
+            state.dropped.count(class);
+        }
+        state.queue.push_back((class, message));
+        drop(state);
+
+        self.message_ready.notify_one();
+    }
+}
+
+/// Creates a channel that applies each message's `MessageClass` policy.
+/// `capacity` only bounds control messages, there is never more than one of each latest-wins class waiting
+pub fn message_channel(capacity: usize) -> (MessageSender, MessageReceiver) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            senders: 1,
+            ..Default::default()
+        }),
+        control_slots: Semaphore::new(capacity),
+        message_ready: Notify::new(),
+    });
+
+    (
+        MessageSender {
+            shared: shared.clone(),
+        },
+        MessageReceiver { shared },
+    )
+}
+
+#[derive(Debug)]
+pub struct MessageSender {
+    shared: Arc<Shared>,
+}
+
+impl MessageSender {
+    /// Only waits for control messages, and only while the queue is full
+    pub async fn send(&self, message: GoliathMessage) -> Result<(), SendError<GoliathMessage>> {
+        let class = MessageClass::of(&message);
+        if class.is_latest_wins() {
+            if self.shared.control_slots.is_closed() {
+                return Err(SendError(message));
+            }
+        } else {
+            match self.shared.control_slots.acquire().await {
+                Ok(permit) => permit.forget(),
+                Err(_) => return Err(SendError(message)),
+            }
+        }
+
+        self.shared.push(class, message);
+        Ok(())
+    }
+
+    /// Latest-wins messages never fail with `Full`
+    pub fn try_send(&self, message: GoliathMessage) -> Result<(), TrySendError<GoliathMessage>> {
+        let class = MessageClass::of(&message);
+        if class.is_latest_wins() {
+            if self.shared.control_slots.is_closed() {
+                return Err(TrySendError::Closed(message));
+            }
+        } else {
+            match self.shared.control_slots.try_acquire() {
+                Ok(permit) => permit.forget(),
+                Err(TryAcquireError::NoPermits) => return Err(TrySendError::Full(message)),
+                Err(TryAcquireError::Closed) => return Err(TrySendError::Closed(message)),
+            }
+        }
+
+        self.shared.push(class, message);
+        Ok(())
+    }
+
+    /// For a message the caller gave up on after `try_send` turned it away
+    pub fn count_dropped(&self, class: MessageClass) {
+        self.shared.state().dropped.count(class);
+    }
+
+    pub fn dropped(&self) -> DroppedFrames {
+        self.shared.state().dropped
+    }
+}
+
+impl Clone for MessageSender {
+    fn clone(&self) -> Self {
+        self.shared.state().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for MessageSender {
+    fn drop(&mut self) {
+        let mut state = self.shared.state();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.shared.message_ready.notify_one();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MessageReceiver {
+    shared: Arc<Shared>,
+}
+
+impl MessageReceiver {
+    /// Returns None once every sender is gone and the queue has been drained
+    pub async fn recv(&mut self) -> Option<GoliathMessage> {
+        loop {
+            match self.try_recv() {
+                Ok(message) => return Some(message),
+                Err(TryRecvError::Disconnected) => return None,
+                Err(TryRecvError::Empty) => self.shared.message_ready.notified().await,
+            }
+        }
+    }
+
+    pub fn try_recv(&mut self) -> Result<GoliathMessage, TryRecvError> {
+        let mut state = self.shared.state();
+        match state.queue.pop_front() {
+            Some((class, message)) => {
+                if !class.is_latest_wins() {
+                    self.shared.control_slots.add_permits(1);
+                }
+                Ok(message)
+            }
+            None if state.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Drops everything that is waiting, counting it as dropped
+    pub fn discard_queued(&mut self) {
+        let mut state = self.shared.state();
+        let State { queue, dropped, .. } = &mut *state;
+        for (class, _) in queue.drain(..) {
+            dropped.count(class);
+            if !class.is_latest_wins() {
+                self.shared.control_slots.add_permits(1);
+            }
+        }
+    }
+
+    pub fn dropped(&self) -> DroppedFrames {
+        self.shared.state().dropped
+    }
+}
+
+impl Drop for MessageReceiver {
+    fn drop(&mut self) {
+        self.shared.control_slots.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{message_channel, DroppedFrames};
+    use crate::protocol::{DriveCommand, GoliathMessage, Heartbeat};
+    use tokio::sync::mpsc::error::{TryRecvError, TrySendError};
+
+    fn drive(left: f32) -> GoliathMessage {
+        GoliathMessage::Drive(DriveCommand {
+            left_track: left,
+            right_track: left,
+        })
+    }
+
+    fn heartbeat(timestamp: u128) -> GoliathMessage {
+        GoliathMessage::Heartbeat(Heartbeat { timestamp })
+    }
+
+    #[test]
+    fn test_message_policies() {
+        let (tx, mut rx) = message_channel(2);
+
+        tx.try_send(drive(0.1)).unwrap();
+        tx.try_send(heartbeat(1)).unwrap();
+        tx.try_send(drive(0.2)).unwrap();
+        tx.try_send(heartbeat(2)).unwrap();
+        // Control messages wait for room, drive commands never do
+        assert!(matches!(
+            tx.try_send(heartbeat(3)),
+            Err(TrySendError::Full(_))
+        ));
+        tx.try_send(drive(0.3)).unwrap();
+
+        // Only the newest drive command is left, and it still comes after what was sent before it
+        assert_eq!(rx.try_recv().unwrap(), heartbeat(1));
+        assert_eq!(rx.try_recv().unwrap(), heartbeat(2));
+        assert_eq!(rx.try_recv().unwrap(), drive(0.3));
+        assert!(matches!(rx.try_recv(), Err(TryRecvError::Empty)));
+        assert_eq!(
+            rx.dropped(),
+            DroppedFrames {
+                drive: 2,
+                ..Default::default()
+            }
+        );
+
+        tx.try_send(heartbeat(3)).unwrap();
+        rx.discard_queued();
+        assert_eq!(tx.dropped().control, 1);
+
+        drop(tx);
+        assert!(matches!(rx.try_recv(), Err(TryRecvError::Disconnected)));
+    }
+}
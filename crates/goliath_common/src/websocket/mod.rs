@@ -1,11 +1,21 @@
-use crate::security::NoVerifier;
-use futures_util::{StreamExt, TryStreamExt};
-use rustls::ClientConfig;
-use std::sync::Arc;
-use tokio::sync::mpsc;
+pub use channel::{message_channel, DroppedFrames, MessageClass, MessageReceiver, MessageSender};
+pub use reconnecting::{
+    Backoff, ConnectionOptions, ConnectionState, NodeCredentials, ReconnectingConnection,
+};
+
+use crate::security::{ClientCertificate, ServerVerification};
+use futures_util::StreamExt;
+use std::{sync::Arc, time::Duration};
+use tokio::{
+    sync::mpsc,
+    time::{interval_at, Instant},
+};
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_tungstenite::{connect_async_tls_with_config, tungstenite, Connector};
 
+mod channel;
+mod reconnecting;
+
 pub type ConnectResult = Result<
     (
         mpsc::Sender<tungstenite::Message>,
@@ -14,11 +24,33 @@ pub type ConnectResult = Result<
     (),
 >;
 
-pub async fn goliath_ws_connect(address: impl Into<String>) -> ConnectResult {
-    let config = ClientConfig::builder()
-        .dangerous()
-        .with_custom_certificate_verifier(Arc::new(NoVerifier))
-        .with_no_client_auth();
+/// How often a connection is pinged, and how long it may stay silent before it is considered dead
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct HeartbeatPolicy {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for HeartbeatPolicy {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(15),
+        }
+    }
+}
+
+/// Frames are never dropped, the socket simply isn't read while the caller falls behind.
+/// Both channels close once the backend goes away, or stays silent for longer than `heartbeat.timeout`
+pub async fn goliath_ws_connect(
+    address: impl Into<String>,
+    server_verification: &ServerVerification,
+    client_certificate: Option<&ClientCertificate>,
+    heartbeat: HeartbeatPolicy,
+) -> ConnectResult {
+    let config = server_verification
+        .client_config(client_certificate)
+        .map_err(|err| log::error!("{err}"))?;
 
     let (stream, _) = connect_async_tls_with_config(
         address.into(),
@@ -33,18 +65,43 @@ pub async fn goliath_ws_connect(address: impl Into<String>) -> ConnectResult {
     let (outgoing_tx, outgoing_rx) = mpsc::channel::<tungstenite::Message>(64);
     let (ws_write, mut ws_read) = stream.split();
     tokio::spawn(ReceiverStream::new(outgoing_rx).map(Ok).forward(ws_write));
-    tokio::spawn(async move {
-        loop {
-            match ws_read.try_next().await {
-                Ok(maybe_msg) => {
-                    if let Some(msg) = maybe_msg {
-                        incoming_tx.try_send(msg).ok();
+    tokio::spawn({
+        // Must not keep the connection alive once the caller has dropped its sender
+        let ping_tx = outgoing_tx.downgrade();
+        async move {
+            let mut ping_interval =
+                interval_at(Instant::now() + heartbeat.interval, heartbeat.interval);
+            let mut last_seen = Instant::now();
+            loop {
+                tokio::select! {
+                    frame = ws_read.next() => match frame {
+                        Some(Ok(msg)) => {
+                            last_seen = Instant::now();
+                            // Waiting here pushes back on the backend, rather than losing the frame
+                            if incoming_tx.send(msg).await.is_err() {
+                                break;
+                            }
+                        }
+                        Some(Err(err)) => {
+                            log::error!("{err}");
+                            break;
+                        }
+                        None => break,
+                    },
+                    _ = ping_interval.tick() => {
+                        if last_seen.elapsed() > heartbeat.timeout {
+                            log::warn!(
+                                "Nothing heard from the backend in {:.1}s, dropping the connection",
+                                last_seen.elapsed().as_secs_f32()
+                            );
+                            break;
+                        }
+                        let Some(ping_tx) = ping_tx.upgrade() else {
+                            break;
+                        };
+                        ping_tx.try_send(tungstenite::Message::Ping(Vec::new())).ok();
                     }
                 }
-                Err(err) => {
-                    log::error!("{err}");
-                    break;
-                }
             }
         }
     });
@@ -0,0 +1,496 @@
+use super::{
+    goliath_ws_connect, message_channel, DroppedFrames, HeartbeatPolicy, MessageReceiver,
+    MessageSender,
+};
+use crate::core::{unix_timestamp_millis, NodeType};
+use crate::protocol::{GoliathMessage, ProtocolError, WireEncoding};
+use crate::security::{
+    generate_registration_mac, AuthScheme, ClientCertificate, RegistrationError,
+    RegistrationRequest, RegistrationResponse, RegistrationStatus, ServerVerification,
+};
+use rand::Rng;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::mpsc::error::{SendError, TryRecvError, TrySendError};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+use tokio_tungstenite::tungstenite::Message;
+
+// The backend only ever waits for a single registration attempt, so if it doesn't answer it never will
+const REGISTRATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where a `ReconnectingConnection` currently stands
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionState {
+    /// Opening a connection, `attempt` counts the failures since the last successful registration
+    Connecting { attempt: u32 },
+    /// Connected, waiting for the registration to go through
+    Connected,
+    /// Registered, messages flow in both directions
+    Registered,
+    /// The connection dropped or could not be established, the next attempt follows after `retry_in`
+    Lost { reason: String, retry_in: Duration },
+    /// The backend turned our credentials down, or another node took over our id.
+    /// Retrying won't change that, so the connection gives up. Finding our id still taken is only
+    /// a `Lost`, the node holding it may go away
+    Rejected(RegistrationError),
+}
+
+/// Jittered exponential backoff between reconnection attempts
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Backoff {
+    /// Doubles with every failed attempt up to `max`, then picks a point in the upper half at random,
+    /// so nodes that lost the backend together don't all come back at the same instant
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .initial
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max);
+        let half = ceiling / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
+
+/// How a node proves who it is during registration
+#[derive(Clone, Debug)]
+pub enum NodeCredentials {
+    /// The node's base64 secret, used to answer the backend's challenge
+    SecretKey(String),
+    /// Presented during the TLS handshake, the backend knows who we are before we register
+    Certificate(ClientCertificate),
+}
+
+#[derive(Clone, Debug)]
+pub struct ConnectionOptions {
+    /// Backend address, as host:port
+    pub address: String,
+    pub id: String,
+    pub node_type: NodeType,
+    pub credentials: NodeCredentials,
+    pub encoding: WireEncoding,
+    pub server_verification: ServerVerification,
+    pub backoff: Backoff,
+    pub heartbeat: HeartbeatPolicy,
+}
+
+impl ConnectionOptions {
+    fn client_certificate(&self) -> Option<&ClientCertificate> {
+        match &self.credentials {
+            NodeCredentials::Certificate(client_certificate) => Some(client_certificate),
+            NodeCredentials::SecretKey(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+enum ConnectError {
+    #[error("Could not connect to the backend")]
+    Connect,
+    #[error("Could not decode key: {0}")]
+    InvalidKey(#[from] base64::DecodeError),
+    #[error("{0}")]
+    Rejected(RegistrationError),
+    #[error("The backend did not complete the registration in time")]
+    RegistrationTimeout,
+    #[error("Lost connection to the backend")]
+    Disconnected,
+    #[error(transparent)]
+    Protocol(#[from] ProtocolError),
+}
+
+// A single websocket connection, as opened by `goliath_ws_connect`
+struct RawConnection {
+    outgoing_tx: mpsc::Sender<Message>,
+    incoming_rx: mpsc::Receiver<Message>,
+    encoding: WireEncoding,
+}
+
+impl RawConnection {
+    async fn send(&self, message: &GoliathMessage) -> Result<(), ConnectError> {
+        self.outgoing_tx
+            .send(message.to_ws_message(self.encoding)?)
+            .await
+            .map_err(|_| ConnectError::Disconnected)
+    }
+
+    // Skips over frames that aren't messages, such as pings
+    async fn recv(&mut self) -> Result<GoliathMessage, ConnectError> {
+        loop {
+            let msg = self
+                .incoming_rx
+                .recv()
+                .await
+                .ok_or(ConnectError::Disconnected)?;
+
+            match GoliathMessage::from_ws_message(&msg) {
+                Ok(message) => return Ok(message),
+                Err(err) => log::debug!("Ignoring frame: {err}"),
+            }
+        }
+    }
+}
+
+// Returns the id the backend registered us under
+async fn register(
+    conn: &mut RawConnection,
+    options: &ConnectionOptions,
+) -> Result<String, ConnectError> {
+    let nonce = loop {
+        match conn.recv().await? {
+            GoliathMessage::Challenge(challenge) => break challenge.nonce,
+            message => log::debug!("Ignoring {message:?} while waiting for the challenge"),
+        }
+    };
+
+    let timestamp = unix_timestamp_millis();
+    let (hash, auth_scheme) = match &options.credentials {
+        NodeCredentials::SecretKey(key) => (
+            generate_registration_mac(&options.id, options.node_type, timestamp, &nonce, key)?,
+            AuthScheme::HmacSha256,
+        ),
+        NodeCredentials::Certificate(_) => (String::new(), AuthScheme::ClientCertificate),
+    };
+
+    conn.send(&GoliathMessage::Registration(RegistrationRequest {
+        id: options.id.clone(),
+        timestamp,
+        hash,
+        node_type: options.node_type,
+        encoding: options.encoding,
+        auth_scheme,
+    }))
+    .await?;
+
+    loop {
+        match conn.recv().await? {
+            GoliathMessage::RegistrationResponse(response) => {
+                return match response.status {
+                    RegistrationStatus::Accepted => {
+                        log::info!("{}", response.msg);
+                        Ok(response.assigned_id.unwrap_or_else(|| options.id.clone()))
+                    }
+                    RegistrationStatus::Rejected(err) => Err(ConnectError::Rejected(err)),
+                };
+            }
+            message => log::debug!("Ignoring {message:?} while waiting for the registration"),
+        }
+    }
+}
+
+async fn connect(
+    options: &ConnectionOptions,
+    state_tx: &watch::Sender<ConnectionState>,
+) -> Result<(RawConnection, String), ConnectError> {
+    let (outgoing_tx, incoming_rx) = goliath_ws_connect(
+        format!("wss://{}", options.address),
+        &options.server_verification,
+        options.client_certificate(),
+        options.heartbeat,
+    )
+    .await
+    .map_err(|_| ConnectError::Connect)?;
+    state_tx.send_replace(ConnectionState::Connected);
+
+    let mut conn = RawConnection {
+        outgoing_tx,
+        incoming_rx,
+        encoding: options.encoding,
+    };
+    let registered_id = timeout(REGISTRATION_TIMEOUT, register(&mut conn, options))
+        .await
+        .map_err(|_| ConnectError::RegistrationTimeout)??;
+
+    Ok((conn, registered_id))
+}
+
+// Returns why the connection was lost, or None once the application has dropped its end.
+// The backend may still turn us away after registration, when another node takes over our id
+async fn relay(
+    conn: &mut RawConnection,
+    outgoing_rx: &mut MessageReceiver,
+    incoming_tx: &MessageSender,
+) -> Option<ConnectError> {
+    loop {
+        tokio::select! {
+            message = outgoing_rx.recv() => {
+                if let Err(err) = conn.send(&message?).await {
+                    return Some(err);
+                }
+            }
+            message = conn.recv() => match message {
+                Ok(GoliathMessage::RegistrationResponse(RegistrationResponse {
+                    status: RegistrationStatus::Rejected(err),
+                    ..
+                })) => return Some(ConnectError::Rejected(err)),
+                Ok(message) => incoming_tx.send(message).await.ok()?,
+                Err(err) => return Some(err),
+            },
+        }
+    }
+}
+
+async fn maintain_connection(
+    options: ConnectionOptions,
+    mut outgoing_rx: MessageReceiver,
+    incoming_tx: MessageSender,
+    state_tx: watch::Sender<ConnectionState>,
+    registered_id_tx: watch::Sender<String>,
+) {
+    let mut attempt = 0;
+    loop {
+        state_tx.send_replace(ConnectionState::Connecting { attempt });
+
+        let err = match connect(&options, &state_tx).await {
+            Ok((mut conn, registered_id)) => {
+                attempt = 0;
+                // Anything queued while we were away is stale by now
+                outgoing_rx.discard_queued();
+                registered_id_tx.send_replace(registered_id);
+                state_tx.send_replace(ConnectionState::Registered);
+
+                match relay(&mut conn, &mut outgoing_rx, &incoming_tx).await {
+                    Some(err) => err,
+                    None => return,
+                }
+            }
+            Err(err) => err,
+        };
+        let reason = match err {
+            // The node holding our id may well go away, so this one is worth another try
+            ConnectError::Rejected(RegistrationError::DuplicateId) => err.to_string(),
+            ConnectError::Rejected(err) => {
+                log::error!("{err}");
+                state_tx.send_replace(ConnectionState::Rejected(err));
+                return;
+            }
+            err => err.to_string(),
+        };
+
+        let retry_in = options.backoff.delay(attempt);
+        attempt = attempt.saturating_add(1);
+        log::warn!("{reason}, retrying in {:.1}s", retry_in.as_secs_f32());
+        state_tx.send_replace(ConnectionState::Lost { reason, retry_in });
+        sleep(retry_in).await;
+    }
+}
+
+/// A connection to the backend that registers itself, and keeps reconnecting and re-registering
+/// whenever it is lost, until the backend rejects it or it is dropped.
+/// Messages sent while it isn't registered are dropped rather than delivered late.
+/// In both directions, only the latest drive command and telemetry reading are kept when the other end falls behind
+#[derive(Debug)]
+pub struct ReconnectingConnection {
+    outgoing_tx: MessageSender,
+    incoming_rx: MessageReceiver,
+    state_rx: watch::Receiver<ConnectionState>,
+    registered_id_rx: watch::Receiver<String>,
+    task: JoinHandle<()>,
+}
+
+impl ReconnectingConnection {
+    /// Must be called from within a tokio runtime
+    pub fn spawn(options: ConnectionOptions) -> Self {
+        let (outgoing_tx, outgoing_rx) = message_channel(64);
+        let (incoming_tx, incoming_rx) = message_channel(64);
+        let (state_tx, state_rx) = watch::channel(ConnectionState::Connecting { attempt: 0 });
+        let (registered_id_tx, registered_id_rx) = watch::channel(options.id.clone());
+
+        Self {
+            outgoing_tx,
+            incoming_rx,
+            state_rx,
+            registered_id_rx,
+            task: tokio::spawn(maintain_connection(
+                options,
+                outgoing_rx,
+                incoming_tx,
+                state_tx,
+                registered_id_tx,
+            )),
+        }
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state_rx.borrow().clone()
+    }
+
+    /// Notified on every state change
+    pub fn watch_state(&self) -> watch::Receiver<ConnectionState> {
+        self.state_rx.clone()
+    }
+
+    /// The id we asked for, unless the backend last registered us under another one
+    pub fn registered_id(&self) -> String {
+        self.registered_id_rx.borrow().clone()
+    }
+
+    pub async fn send(&self, message: GoliathMessage) -> Result<(), SendError<GoliathMessage>> {
+        self.outgoing_tx.send(message).await
+    }
+
+    pub fn try_send(&self, message: GoliathMessage) -> Result<(), TrySendError<GoliathMessage>> {
+        self.outgoing_tx.try_send(message)
+    }
+
+    /// Only returns None once the connection has given up
+    pub async fn recv(&mut self) -> Option<GoliathMessage> {
+        self.incoming_rx.recv().await
+    }
+
+    pub fn try_recv(&mut self) -> Result<GoliathMessage, TryRecvError> {
+        self.incoming_rx.try_recv()
+    }
+
+    /// Messages from the backend that were superseded before the application got to them
+    pub fn dropped_incoming(&self) -> DroppedFrames {
+        self.incoming_rx.dropped()
+    }
+
+    /// Messages that were superseded before they were sent, or went stale while the connection was down
+    pub fn dropped_outgoing(&self) -> DroppedFrames {
+        self.outgoing_tx.dropped()
+    }
+}
+
+impl Drop for ReconnectingConnection {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Backoff, ConnectionOptions, ConnectionState, NodeCredentials, ReconnectingConnection,
+    };
+    use crate::core::NodeType;
+    use crate::protocol::{GoliathMessage, Heartbeat};
+    use crate::security::{RegistrationError, RegistrationResponse, ServerVerification};
+    use crate::test_utils::{recv, FakeBackend, TestIdentity};
+    use crate::websocket::HeartbeatPolicy;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    const LIMIT: Duration = Duration::from_secs(5);
+
+    fn options(backend: &FakeBackend, identity: &TestIdentity) -> ConnectionOptions {
+        ConnectionOptions {
+            address: backend.address(),
+            id: "EmilyVehicle".to_string(),
+            node_type: NodeType::Vehicle,
+            credentials: NodeCredentials::SecretKey(
+                "Hqgca8UsJBwEO4io0GxMagU3WMmVsJK/cv9zX3sHL84=".to_string(),
+            ),
+            encoding: Default::default(),
+            server_verification: ServerVerification::Fingerprint(identity.fingerprint()),
+            backoff: Backoff {
+                initial: Duration::from_millis(50),
+                max: Duration::from_millis(100),
+            },
+            heartbeat: HeartbeatPolicy::default(),
+        }
+    }
+
+    async fn wait_for_state(
+        connection: &ReconnectingConnection,
+        condition: impl Fn(&ConnectionState) -> bool,
+    ) {
+        timeout(LIMIT, connection.watch_state().wait_for(condition))
+            .await
+            .expect("Connection state did not change in time")
+            .unwrap();
+    }
+
+    #[test]
+    fn test_backoff_bounds() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(8),
+        };
+
+        for (attempt, ceiling) in [(0, 1), (1, 2), (2, 4), (3, 8), (4, 8), (u32::MAX, 8)] {
+            let ceiling = Duration::from_secs(ceiling);
+            for _ in 0..100 {
+                let delay = backoff.delay(attempt);
+                assert!(delay >= ceiling / 2 && delay <= ceiling, "{delay:?}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_registration_responses() {
+        let identity = TestIdentity::self_signed();
+        let backend = FakeBackend::start(&identity).await;
+
+        let accepted = ReconnectingConnection::spawn(options(&backend, &identity));
+        let _accepted_conn = backend
+            .accept_registration(RegistrationResponse::accepted())
+            .await;
+        wait_for_state(&accepted, |state| *state == ConnectionState::Registered).await;
+
+        let rejected = ReconnectingConnection::spawn(options(&backend, &identity));
+        let _rejected_conn = backend
+            .accept_registration(RegistrationResponse::rejected(
+                RegistrationError::MismatchedHash,
+            ))
+            .await;
+        wait_for_state(&rejected, |state| {
+            *state == ConnectionState::Rejected(RegistrationError::MismatchedHash)
+        })
+        .await;
+        // The same credentials would only be rejected again, so it doesn't come back
+        assert!(timeout(Duration::from_millis(300), backend.accept())
+            .await
+            .is_err());
+        assert_eq!(accepted.state(), ConnectionState::Registered);
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_after_losing_the_backend() {
+        let identity = TestIdentity::self_signed();
+        let backend = FakeBackend::start(&identity).await;
+        let heartbeat = |timestamp| GoliathMessage::Heartbeat(Heartbeat { timestamp });
+
+        let connection = ReconnectingConnection::spawn(options(&backend, &identity));
+        assert_eq!(
+            connection.state(),
+            ConnectionState::Connecting { attempt: 0 }
+        );
+        let backend_conn = backend
+            .accept_registration(RegistrationResponse::accepted())
+            .await;
+        wait_for_state(&connection, |state| *state == ConnectionState::Registered).await;
+
+        drop(backend_conn);
+        wait_for_state(&connection, |state| {
+            matches!(state, ConnectionState::Lost { .. })
+        })
+        .await;
+
+        // Queued while nothing could deliver it, it is stale by the time something can
+        connection.send(heartbeat(1)).await.unwrap();
+        let mut backend_conn = backend
+            .accept_registration(RegistrationResponse::accepted())
+            .await;
+        wait_for_state(&connection, |state| *state == ConnectionState::Registered).await;
+        assert_eq!(connection.dropped_outgoing().control, 1);
+
+        connection.send(heartbeat(2)).await.unwrap();
+        assert_eq!(recv(&mut backend_conn).await, heartbeat(2));
+    }
+}
@@ -0,0 +1,132 @@
+mod watchdog;
+
+use crate::config::VehicleConfig;
+use crate::hardware::Tracks;
+use goliath_common::{
+    protocol::{GoliathMessage, Safety, SessionControl},
+    security::RegistrationError,
+    websocket::{ConnectionState, ReconnectingConnection},
+};
+use thiserror::Error;
+use tokio::time::{interval, MissedTickBehavior};
+use watchdog::Watchdog;
+
+#[derive(Debug, Error)]
+pub enum AgentError {
+    #[error("No way to verify the backend is configured")]
+    Unverified,
+    #[error("{0}")]
+    Rejected(RegistrationError),
+    #[error("Connection to the backend gave up")]
+    ConnectionClosed,
+}
+
+fn report_status(connection: &ReconnectingConnection, watchdog: &Watchdog) {
+    connection
+        .try_send(GoliathMessage::Safety(Safety::Status(
+            watchdog.status().clone(),
+        )))
+        .map_err(|err| log::warn!("Could not report the safety status: {err}"))
+        .ok();
+}
+
+/// Stays connected to the backend and follows its commands, only returns once the connection gave up,
+/// usually because the backend turned us down
+pub async fn run(config: &VehicleConfig, tracks: &mut Tracks) -> Result<(), AgentError> {
+    let mut connection =
+        ReconnectingConnection::spawn(config.connection_options().ok_or(AgentError::Unverified)?);
+    let mut state_rx = connection.watch_state();
+
+    let mut telemetry_interval = interval(config.hardware.telemetry_interval());
+    telemetry_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut watchdog = Watchdog::new(config.failsafe.deadline());
+    // Checked several times per deadline, so it is never overshot by much
+    let mut watchdog_interval = interval(config.failsafe.deadline() / 4);
+    watchdog_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut in_session = false;
+
+    loop {
+        tokio::select! {
+            message = connection.recv() => {
+                let Some(message) = message else {
+                    watchdog.trip("Connection gave up");
+                    tracks.stop();
+                    // The rejection is usually what ended it, and says more than the closed channel
+                    return Err(match connection.state() {
+                        ConnectionState::Rejected(err) => AgentError::Rejected(err),
+                        _ => AgentError::ConnectionClosed,
+                    });
+                };
+                match message {
+                    GoliathMessage::Drive(command) if in_session => {
+                        watchdog.feed();
+                        if watchdog.is_armed() {
+                            tracks.apply(command);
+                        }
+                    }
+                    GoliathMessage::Heartbeat(_) if in_session => watchdog.feed(),
+                    GoliathMessage::Safety(Safety::Rearm) if in_session => {
+                        if watchdog.rearm() {
+                            log::info!("Re-armed by the client");
+                            report_status(&connection, &watchdog);
+                        }
+                    }
+                    GoliathMessage::Session(SessionControl::Started { session_id, peer_id }) => {
+                        log::info!("Session {session_id} started with {peer_id}");
+                        in_session = true;
+                        report_status(&connection, &watchdog);
+                    }
+                    GoliathMessage::Session(SessionControl::Ended { session_id, reason }) => {
+                        log::info!("Session {session_id} ended: {reason}");
+                        let dropped = connection.dropped_incoming();
+                        if dropped.drive > 0 {
+                            log::info!(
+                                "{} drive commands were superseded before they were applied",
+                                dropped.drive
+                            );
+                        }
+                        in_session = false;
+                        watchdog.trip("Session ended");
+                        tracks.stop();
+                    }
+                    GoliathMessage::Shutdown(notice) => {
+                        // The connection is about to drop anyway, but there's no reason to wait for it
+                        log::warn!("Backend is going away: {}", notice.reason);
+                        in_session = false;
+                        watchdog.trip("Backend shut down");
+                        tracks.stop();
+                    }
+                    GoliathMessage::Error(err) => {
+                        log::warn!("Backend reported an error: {}", err.msg)
+                    }
+                    message => log::debug!("Ignoring {message:?}"),
+                }
+            }
+            Ok(()) = state_rx.changed() => match state_rx.borrow_and_update().clone() {
+                ConnectionState::Registered => {
+                    log::info!("Registered as {}", connection.registered_id())
+                }
+                ConnectionState::Rejected(err) => {
+                    watchdog.trip("Registration rejected");
+                    tracks.stop();
+                    return Err(AgentError::Rejected(err));
+                }
+                _ => {
+                    // Never keep driving on the last command while nobody is in control
+                    in_session = false;
+                    watchdog.trip("Lost connection to the backend");
+                    tracks.stop();
+                }
+            },
+            _ = watchdog_interval.tick() => {
+                if watchdog.check() {
+                    tracks.stop();
+                    report_status(&connection, &watchdog);
+                }
+            }
+            _ = telemetry_interval.tick(), if in_session => {
+                connection.try_send(GoliathMessage::Telemetry(tracks.telemetry())).ok();
+            }
+        }
+    }
+}
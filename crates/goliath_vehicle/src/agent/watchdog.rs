@@ -0,0 +1,95 @@
+use goliath_common::protocol::SafetyStatus;
+use std::time::{Duration, Instant};
+
+/// Trips into failsafe when commands stop arriving, and only leaves it on an explicit re-arm
+#[derive(Debug)]
+pub struct Watchdog {
+    deadline: Duration,
+    last_command: Instant,
+    status: SafetyStatus,
+}
+
+impl Watchdog {
+    /// Starts out in failsafe, a client has to arm the vehicle before it drives
+    pub fn new(deadline: Duration) -> Self {
+        Self {
+            deadline,
+            last_command: Instant::now(),
+            status: SafetyStatus::Failsafe {
+                reason: "Not armed yet".to_string(),
+            },
+        }
+    }
+
+    pub fn status(&self) -> &SafetyStatus {
+        &self.status
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.status == SafetyStatus::Armed
+    }
+
+    /// Called for every drive command or heartbeat from the client in control
+    pub fn feed(&mut self) {
+        self.last_command = Instant::now();
+    }
+
+    /// Returns whether the status changed
+    pub fn rearm(&mut self) -> bool {
+        self.feed();
+        let changed = !self.is_armed();
+        self.status = SafetyStatus::Armed;
+        changed
+    }
+
+    /// Returns whether the status changed, tripping an already tripped watchdog keeps the first reason
+    pub fn trip(&mut self, reason: &str) -> bool {
+        if !self.is_armed() {
+            return false;
+        }
+
+        log::warn!("Entering failsafe: {reason}");
+        self.status = SafetyStatus::Failsafe {
+            reason: reason.to_string(),
+        };
+        true
+    }
+
+    /// Trips the watchdog once the deadline has passed without a command, returns whether it did
+    pub fn check(&mut self) -> bool {
+        self.is_armed()
+            && self.last_command.elapsed() > self.deadline
+            && self.trip(&format!(
+                "No command received in {}ms",
+                self.deadline.as_millis()
+            ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Watchdog;
+    use goliath_common::protocol::SafetyStatus;
+    use std::time::Duration;
+
+    #[test]
+    fn test_trips_until_rearmed() {
+        let mut watchdog = Watchdog::new(Duration::from_millis(20));
+        assert!(!watchdog.is_armed());
+        assert!(!watchdog.check());
+
+        assert!(watchdog.rearm());
+        assert!(!watchdog.rearm());
+        watchdog.feed();
+        assert!(!watchdog.check());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(watchdog.check());
+        assert!(matches!(watchdog.status(), SafetyStatus::Failsafe { .. }));
+
+        // Commands alone don't get it out of failsafe
+        watchdog.feed();
+        assert!(!watchdog.check());
+        assert!(!watchdog.is_armed());
+    }
+}
@@ -0,0 +1,298 @@
+use clap::Args;
+use goliath_common::{
+    core::NodeType,
+    protocol::WireEncoding,
+    security::{derive_node_key, ClientCertificate, ServerVerification, TlsOverrides},
+    websocket::{Backoff, ConnectionOptions, HeartbeatPolicy, NodeCredentials},
+};
+use serde::Deserialize;
+use std::{fs, path::PathBuf, time::Duration};
+use thiserror::Error;
+
+const DEFAULT_CONFIG: &str = "goliath_vehicle.toml";
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Could not read config file {0}: {1}")]
+    Io(PathBuf, std::io::Error),
+    #[error("Config file {0} is malformed: {1}")]
+    Malformed(PathBuf, toml::de::Error),
+    #[error("Invalid configuration:\n  {}", .0.join("\n  "))]
+    Invalid(Vec<String>),
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HardwareConfig {
+    /// Scales every drive command, 1.0 lets the tracks run at full power
+    pub max_throttle: f32,
+    /// Set when a motor is mounted the other way around
+    pub invert_left: bool,
+    pub invert_right: bool,
+    /// How often telemetry is reported while in a session
+    pub telemetry_interval_ms: u64,
+}
+
+impl Default for HardwareConfig {
+    fn default() -> Self {
+        Self {
+            max_throttle: 1.0,
+            invert_left: false,
+            invert_right: false,
+            telemetry_interval_ms: 500,
+        }
+    }
+}
+
+impl HardwareConfig {
+    pub fn telemetry_interval(&self) -> Duration {
+        Duration::from_millis(self.telemetry_interval_ms)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FailsafeConfig {
+    /// How long the client in control may stay silent before the tracks are stopped
+    pub deadline_ms: u64,
+}
+
+impl Default for FailsafeConfig {
+    fn default() -> Self {
+        Self { deadline_ms: 1000 }
+    }
+}
+
+impl FailsafeConfig {
+    pub fn deadline(&self) -> Duration {
+        Duration::from_millis(self.deadline_ms)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    /// How often the backend is pinged
+    pub interval_secs: u64,
+    /// How long the backend may stay silent before the connection is considered lost
+    pub timeout_secs: u64,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval_secs: 5,
+            timeout_secs: 15,
+        }
+    }
+}
+
+/// Everything the vehicle can be configured with, read from a TOML file, then overridden by
+/// environment variables and finally by command line flags
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VehicleConfig {
+    /// Backend address, as host:port
+    pub server_address: String,
+    pub vehicle_id: String,
+    pub key: String,
+    pub wire_encoding: WireEncoding,
+    pub server_verification: Option<ServerVerification>,
+    /// When set, the vehicle registers through its certificate rather than `key`
+    pub client_certificate: Option<ClientCertificate>,
+    pub hardware: HardwareConfig,
+    pub failsafe: FailsafeConfig,
+    pub heartbeat: HeartbeatConfig,
+}
+
+impl Default for VehicleConfig {
+    fn default() -> Self {
+        Self {
+            server_address: "localhost:8555".to_string(),
+            vehicle_id: String::new(),
+            key: String::new(),
+            wire_encoding: WireEncoding::Bincode,
+            server_verification: None,
+            client_certificate: None,
+            hardware: HardwareConfig::default(),
+            failsafe: FailsafeConfig::default(),
+            heartbeat: HeartbeatConfig::default(),
+        }
+    }
+}
+
+/// Set on the command line or in the environment, e.g. by the vehicle's service unit, and
+/// applied on top of the TOML file
+#[derive(Debug, Default, Args)]
+pub struct ConfigOverrides {
+    /// Path to the TOML config file, built-in defaults are used if it doesn't exist
+    #[arg(long, env = "GOLIATH_VEHICLE_CONFIG", default_value = DEFAULT_CONFIG)]
+    pub config: PathBuf,
+    /// Backend address, as host:port
+    #[arg(long, env = "GOLIATH_SERVER_ADDRESS")]
+    pub server: Option<String>,
+    #[arg(long, env = "GOLIATH_VEHICLE_ID")]
+    pub vehicle_id: Option<String>,
+    #[arg(long, env = "GOLIATH_SECRET_KEY", hide_env_values = true)]
+    pub key: Option<String>,
+    #[arg(long, env = "GOLIATH_WIRE_ENCODING", value_enum)]
+    pub wire_encoding: Option<WireEncoding>,
+    #[command(flatten)]
+    pub tls: TlsOverrides,
+    #[arg(long, env = "GOLIATH_MAX_THROTTLE")]
+    pub max_throttle: Option<f32>,
+}
+
+// Overwrites the target only when an override was given
+fn apply<T>(target: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *target = value;
+    }
+}
+
+impl VehicleConfig {
+    pub fn load(overrides: ConfigOverrides) -> Result<Self, ConfigError> {
+        let mut config = match fs::read_to_string(&overrides.config) {
+            Ok(contents) => Self::parse(&contents)
+                .map_err(|err| ConfigError::Malformed(overrides.config.clone(), err))?,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(err) => return Err(ConfigError::Io(overrides.config, err)),
+        };
+
+        config.apply(overrides);
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(contents)
+    }
+
+    fn apply(&mut self, overrides: ConfigOverrides) {
+        apply(&mut self.server_address, overrides.server);
+        apply(&mut self.vehicle_id, overrides.vehicle_id);
+        apply(&mut self.key, overrides.key);
+        apply(&mut self.wire_encoding, overrides.wire_encoding);
+        apply(&mut self.hardware.max_throttle, overrides.max_throttle);
+
+        overrides
+            .tls
+            .apply(&mut self.server_verification, &mut self.client_certificate);
+    }
+
+    /// Lists all problems together, a vehicle out in the field is a slow thing to restart
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        if self.server_address.is_empty() {
+            problems.push("server_address must not be empty".to_string());
+        }
+        if self.vehicle_id.is_empty() {
+            problems.push("vehicle_id must not be empty".to_string());
+        }
+        if self.client_certificate.is_none()
+            && derive_node_key(&self.vehicle_id, &self.key).is_err()
+        {
+            problems.push("key must be valid base64, unless client_certificate is set".to_string());
+        }
+        if self.server_verification.is_none() {
+            problems.push(
+                "server_verification must be set, there is no other way to trust the backend"
+                    .to_string(),
+            );
+        }
+        if !(self.hardware.max_throttle > 0.0 && self.hardware.max_throttle <= 1.0) {
+            problems.push(format!(
+                "hardware.max_throttle ({}) must be within (0.0, 1.0]",
+                self.hardware.max_throttle
+            ));
+        }
+        if self.hardware.telemetry_interval_ms == 0 {
+            problems.push("hardware.telemetry_interval_ms must be greater than 0".to_string());
+        }
+        if self.failsafe.deadline_ms == 0 {
+            problems.push("failsafe.deadline_ms must be greater than 0".to_string());
+        }
+        if self.heartbeat.interval_secs == 0 {
+            problems.push("heartbeat.interval_secs must be greater than 0".to_string());
+        }
+        if self.heartbeat.timeout_secs <= self.heartbeat.interval_secs {
+            problems.push(format!(
+                "heartbeat.timeout_secs ({}) must be greater than heartbeat.interval_secs ({})",
+                self.heartbeat.timeout_secs, self.heartbeat.interval_secs
+            ));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    /// None without a way to verify the backend, which `validate` already rules out
+    pub fn connection_options(&self) -> Option<ConnectionOptions> {
+        Some(ConnectionOptions {
+            address: self.server_address.clone(),
+            id: self.vehicle_id.clone(),
+            node_type: NodeType::Vehicle,
+            credentials: match &self.client_certificate {
+                Some(client_certificate) => {
+                    NodeCredentials::Certificate(client_certificate.clone())
+                }
+                None => NodeCredentials::SecretKey(self.key.clone()),
+            },
+            encoding: self.wire_encoding,
+            server_verification: self.server_verification.clone()?,
+            backoff: Backoff::default(),
+            heartbeat: HeartbeatPolicy {
+                interval: Duration::from_secs(self.heartbeat.interval_secs),
+                timeout: Duration::from_secs(self.heartbeat.timeout_secs),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConfigError, ConfigOverrides, VehicleConfig};
+    use goliath_common::security::ServerVerification;
+
+    #[test]
+    fn test_layered_config() {
+        let mut config = VehicleConfig::parse(
+            r#"
+            vehicle_id = "Goliath1"
+            key = "R29saWF0aDFTZWNyZXQ="
+
+            [server_verification]
+            Fingerprint = "abcd"
+
+            [hardware]
+            invert_left = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.server_verification,
+            Some(ServerVerification::Fingerprint("abcd".to_string()))
+        );
+        assert!(config.hardware.invert_left);
+        assert!(config.validate().is_ok());
+
+        config.apply(ConfigOverrides {
+            key: Some("not base64!".to_string()),
+            max_throttle: Some(1.5),
+            ..Default::default()
+        });
+
+        // Both problems are reported together
+        match config.validate() {
+            Err(ConfigError::Invalid(problems)) => assert_eq!(problems.len(), 2),
+            res => panic!("Expected an invalid config, got {res:?}"),
+        }
+
+        assert!(VehicleConfig::parse("vehicle = \"Goliath1\"").is_err());
+    }
+}
@@ -0,0 +1,65 @@
+use crate::config::HardwareConfig;
+use goliath_common::{
+    core::unix_timestamp_millis,
+    protocol::{DriveCommand, Telemetry},
+};
+
+/// Stand-in for the motor driver, the track outputs are only logged until real hardware is wired up
+#[derive(Debug)]
+pub struct Tracks {
+    config: HardwareConfig,
+    // As commanded, after scaling but before inversion, which only concerns how the motors are mounted
+    left: f32,
+    right: f32,
+}
+
+impl Tracks {
+    pub fn new(config: HardwareConfig) -> Self {
+        Self {
+            config,
+            left: 0.0,
+            right: 0.0,
+        }
+    }
+
+    // Anything that isn't a number stops the track rather than being passed on to the motor
+    fn scale(&self, value: f32) -> f32 {
+        if value.is_finite() {
+            value.clamp(-1.0, 1.0) * self.config.max_throttle
+        } else {
+            0.0
+        }
+    }
+
+    pub fn apply(&mut self, command: DriveCommand) {
+        self.left = self.scale(command.left_track);
+        self.right = self.scale(command.right_track);
+        self.write_outputs();
+    }
+
+    pub fn stop(&mut self) {
+        if self.left != 0.0 || self.right != 0.0 {
+            self.left = 0.0;
+            self.right = 0.0;
+            self.write_outputs();
+        }
+    }
+
+    fn write_outputs(&self) {
+        let output = |value: f32, inverted: bool| if inverted { -value } else { value };
+        log::debug!(
+            "Tracks set to left {:.2}, right {:.2}",
+            output(self.left, self.config.invert_left),
+            output(self.right, self.config.invert_right)
+        );
+    }
+
+    pub fn telemetry(&self) -> Telemetry {
+        Telemetry {
+            timestamp: unix_timestamp_millis(),
+            left_track: self.left,
+            right_track: self.right,
+            battery_voltage: None,
+        }
+    }
+}
@@ -1,20 +1,29 @@
-use goliath_common::{logging::setup_logger, websocket::goliath_ws_connect};
+use clap::Parser;
+use config::VehicleConfig;
+use goliath_common::logging::setup_logger;
+use hardware::Tracks;
+
+mod agent;
+mod config;
+mod hardware;
+
+#[derive(Parser)]
+#[command(about = "Goliath vehicle agent")]
+struct Cli {
+    #[command(flatten)]
+    config: config::ConfigOverrides,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), ()> {
+    let cli = Cli::parse();
     setup_logger();
 
-    let (outgoing_tx, _outgoing_rx) =
-        goliath_ws_connect("wss://localhost:8555".to_string()).await?;
-
-    for _ in 0..100 {
-        outgoing_tx
-            .send(tokio_tungstenite::tungstenite::Message::Text(
-                "Hello from vehicle".to_string(),
-            ))
-            .await
-            .ok();
-    }
+    let config = VehicleConfig::load(cli.config).map_err(|err| log::error!("{err}"))?;
+    let mut tracks = Tracks::new(config.hardware.clone());
 
-    Ok(())
+    log::info!("Starting vehicle {}", config.vehicle_id);
+    agent::run(&config, &mut tracks)
+        .await
+        .map_err(|err| log::error!("{err}"))
 }